@@ -0,0 +1,180 @@
+//! Access lists
+//!
+//! Decides whose audio crosses the bridge. Deny entries always win, if there are any
+//! allow entries for a platform, only speakers matching one of them are bridged.
+
+use std::fmt;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+pub type AccessHandle = Arc<std::sync::Mutex<AccessList>>;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AccessConfig {
+	/// Bridge discord bot accounts
+	pub bridge_bots: bool,
+	pub allow: AccessRules,
+	pub deny: AccessRules,
+}
+
+impl Default for AccessConfig {
+	fn default() -> Self { Self { bridge_bots: true, allow: Default::default(), deny: Default::default() } }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AccessRules {
+	pub discord_users: Vec<u64>,
+	pub discord_roles: Vec<u64>,
+	/// Teamspeak client database IDs
+	pub ts_clients: Vec<u64>,
+	pub ts_server_groups: Vec<u64>,
+}
+
+/// A single allow or deny entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessEntry {
+	DiscordUser(u64),
+	DiscordRole(u64),
+	TsClient(u64),
+	TsServerGroup(u64),
+}
+
+impl fmt::Display for AccessEntry {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			AccessEntry::DiscordUser(id) => write!(f, "<@{}>", id),
+			AccessEntry::DiscordRole(id) => write!(f, "<@&{}>", id),
+			AccessEntry::TsClient(id) => write!(f, "TS client {}", id),
+			AccessEntry::TsServerGroup(id) => write!(f, "TS server group {}", id),
+		}
+	}
+}
+
+impl AccessRules {
+	fn list_mut(&mut self, entry: AccessEntry) -> (&mut Vec<u64>, u64) {
+		match entry {
+			AccessEntry::DiscordUser(id) => (&mut self.discord_users, id),
+			AccessEntry::DiscordRole(id) => (&mut self.discord_roles, id),
+			AccessEntry::TsClient(id) => (&mut self.ts_clients, id),
+			AccessEntry::TsServerGroup(id) => (&mut self.ts_server_groups, id),
+		}
+	}
+
+	fn add(&mut self, entry: AccessEntry) {
+		let (list, id) = self.list_mut(entry);
+		if !list.contains(&id) {
+			list.push(id);
+		}
+	}
+
+	/// Returns whether the entry existed
+	fn remove(&mut self, entry: AccessEntry) -> bool {
+		let (list, id) = self.list_mut(entry);
+		let len = list.len();
+		list.retain(|v| *v != id);
+		list.len() != len
+	}
+
+	fn entries(&self) -> impl Iterator<Item = AccessEntry> + '_ {
+		self.discord_users.iter().map(|id| AccessEntry::DiscordUser(*id))
+			.chain(self.discord_roles.iter().map(|id| AccessEntry::DiscordRole(*id)))
+			.chain(self.ts_clients.iter().map(|id| AccessEntry::TsClient(*id)))
+			.chain(self.ts_server_groups.iter().map(|id| AccessEntry::TsServerGroup(*id)))
+	}
+
+	fn matches_discord(&self, user: u64, roles: &[u64]) -> bool {
+		self.discord_users.contains(&user) || roles.iter().any(|r| self.discord_roles.contains(r))
+	}
+
+	fn matches_teamspeak(&self, client: u64, groups: &[u64]) -> bool {
+		self.ts_clients.contains(&client) || groups.iter().any(|g| self.ts_server_groups.contains(g))
+	}
+}
+
+/// Access rules at runtime, changed by commands.
+pub struct AccessList {
+	config: AccessConfig,
+	/// Increased on every change
+	generation: u64,
+}
+
+impl AccessList {
+	pub fn new(config: AccessConfig) -> Self { Self { config, generation: 0 } }
+
+	/// Changes whenever the rules change
+	pub fn generation(&self) -> u64 { self.generation }
+
+	pub fn config(&self) -> &AccessConfig { &self.config }
+
+	/// Replace all rules, like after importing settings
+	pub fn set_config(&mut self, config: AccessConfig) {
+		self.config = config;
+		self.generation += 1;
+	}
+
+	/// Whether any rule applies to discord speakers, unknown speakers are only bridged without rules.
+	pub fn has_discord_rules(&self) -> bool {
+		let config = &self.config;
+		!config.bridge_bots
+			|| !config.allow.discord_users.is_empty()
+			|| !config.allow.discord_roles.is_empty()
+			|| !config.deny.discord_users.is_empty()
+			|| !config.deny.discord_roles.is_empty()
+	}
+
+	pub fn discord_allowed(&self, user: u64, roles: &[u64], bot: bool) -> bool {
+		let config = &self.config;
+		if bot && !config.bridge_bots {
+			return false;
+		}
+		if config.deny.matches_discord(user, roles) {
+			return false;
+		}
+		(config.allow.discord_users.is_empty() && config.allow.discord_roles.is_empty())
+			|| config.allow.matches_discord(user, roles)
+	}
+
+	pub fn teamspeak_allowed(&self, client: u64, groups: &[u64]) -> bool {
+		let config = &self.config;
+		if config.deny.matches_teamspeak(client, groups) {
+			return false;
+		}
+		(config.allow.ts_clients.is_empty() && config.allow.ts_server_groups.is_empty())
+			|| config.allow.matches_teamspeak(client, groups)
+	}
+
+	/// Add an allow entry, removes a deny entry for the same target.
+	pub fn allow(&mut self, entry: AccessEntry) {
+		self.config.deny.remove(entry);
+		self.config.allow.add(entry);
+		self.generation += 1;
+	}
+
+	/// Add a deny entry, removes an allow entry for the same target.
+	pub fn deny(&mut self, entry: AccessEntry) {
+		self.config.allow.remove(entry);
+		self.config.deny.add(entry);
+		self.generation += 1;
+	}
+
+	/// Remove allow and deny entries, returns whether there were any.
+	pub fn remove(&mut self, entry: AccessEntry) -> bool {
+		let removed = self.config.allow.remove(entry) | self.config.deny.remove(entry);
+		self.generation += 1;
+		removed
+	}
+
+	pub fn set_bridge_bots(&mut self, bridge: bool) {
+		self.config.bridge_bots = bridge;
+		self.generation += 1;
+	}
+
+	pub fn bridge_bots(&self) -> bool { self.config.bridge_bots }
+
+	pub fn allowed(&self) -> impl Iterator<Item = AccessEntry> + '_ { self.config.allow.entries() }
+
+	pub fn denied(&self) -> impl Iterator<Item = AccessEntry> + '_ { self.config.deny.entries() }
+}
@@ -0,0 +1,285 @@
+//! Packet capture
+//!
+//! Writes every received Opus packet of both sides with its arrival time into a capture file,
+//! so choppy audio can be reproduced later. `voice_bridge replay <file>` feeds a capture
+//! through the jitter buffers offline and writes the result as WAV files.
+//!
+//! A capture starts with [`MAGIC`], followed by records of
+//! `source: u8, arrival_us: u64, id: u32, sequence: u16, timestamp: u32, len: u32, data`,
+//! all little endian. Discord records contain the SSRC, RTP sequence and timestamp and the
+//! Opus payload. Teamspeak records contain the client ID, packet ID and the whole packet.
+
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use slog::{o, Logger};
+use tsclientlib::ClientId;
+use tsproto_packets::packets::{Direction, InAudioBuf};
+
+use crate::recorder::Source;
+use crate::status::LossCounter;
+use crate::{discord_audiohandler, ConnectionId, TsAudioHandler, FRAME_SIZE_MS, SAMPLE_RATE, STEREO_20MS};
+
+const MAGIC: &[u8] = b"VBCAP1\n";
+/// Keep playing this long after the last packet, so the buffers run empty
+const DRAIN_TIME: Duration = Duration::from_secs(1);
+
+pub type CaptureHandle = Arc<std::sync::Mutex<Capture>>;
+
+/// A running capture, does nothing if disabled.
+pub struct Capture {
+	start: Instant,
+	writer: Option<BufWriter<File>>,
+}
+
+struct Record {
+	source: Source,
+	arrival: Duration,
+	id: u32,
+	sequence: u16,
+	data: Vec<u8>,
+}
+
+impl Capture {
+	pub fn disabled() -> Self { Self { start: Instant::now(), writer: None } }
+
+	/// Start capturing into a new file in `dir`, returns the file path.
+	pub fn start(dir: &Path) -> Result<(Self, PathBuf)> {
+		std::fs::create_dir_all(dir).with_context(|| format!("Can't create capture directory {}", dir.display()))?;
+		let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+		let path = dir.join(format!("{}.vbcap", timestamp));
+		let mut writer = BufWriter::new(File::create(&path).with_context(|| format!("Can't create capture {}", path.display()))?);
+		writer.write_all(MAGIC)?;
+		Ok((Self { start: Instant::now(), writer: Some(writer) }, path))
+	}
+
+	/// Add a received packet, stops capturing on write errors.
+	pub fn push(&mut self, source: Source, id: u32, sequence: u16, timestamp: u32, data: &[u8]) {
+		let writer = match &mut self.writer {
+			Some(v) => v,
+			None => return,
+		};
+		let source = match source {
+			Source::Discord => 0u8,
+			Source::Teamspeak => 1u8,
+		};
+		let arrival = self.start.elapsed().as_micros() as u64;
+		let result = writer.write_all(&[source])
+			.and_then(|_| writer.write_all(&arrival.to_le_bytes()))
+			.and_then(|_| writer.write_all(&id.to_le_bytes()))
+			.and_then(|_| writer.write_all(&sequence.to_le_bytes()))
+			.and_then(|_| writer.write_all(&timestamp.to_le_bytes()))
+			.and_then(|_| writer.write_all(&(data.len() as u32).to_le_bytes()))
+			.and_then(|_| writer.write_all(data));
+		if let Err(e) = result {
+			eprintln!("Stopped capturing packets: {}", e);
+			self.writer = None;
+		}
+	}
+
+	/// Write everything buffered to disk
+	pub fn finish(&mut self) -> Result<()> {
+		if let Some(mut writer) = self.writer.take() {
+			writer.flush()?;
+		}
+		Ok(())
+	}
+}
+
+fn read_records(path: &Path) -> Result<Vec<Record>> {
+	let mut reader = BufReader::new(File::open(path).with_context(|| format!("Can't open capture {}", path.display()))?);
+	let mut magic = [0; MAGIC.len()];
+	reader.read_exact(&mut magic)?;
+	if magic != MAGIC {
+		bail!("{} is not a packet capture", path.display());
+	}
+	let mut records = Vec::new();
+	loop {
+		let mut header = [0; 23];
+		match reader.read_exact(&mut header) {
+			Ok(()) => (),
+			Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+			Err(e) => return Err(e.into()),
+		}
+		let source = match header[0] {
+			0 => Source::Discord,
+			1 => Source::Teamspeak,
+			s => bail!("Unknown source {} in capture", s),
+		};
+		let arrival = Duration::from_micros(u64::from_le_bytes(header[1..9].try_into()?));
+		let id = u32::from_le_bytes(header[9..13].try_into()?);
+		let sequence = u16::from_le_bytes(header[13..15].try_into()?);
+		let len = u32::from_le_bytes(header[19..23].try_into()?) as usize;
+		let mut data = vec![0; len];
+		match reader.read_exact(&mut data) {
+			Ok(()) => (),
+			// the bridge stopped while writing
+			Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+			Err(e) => return Err(e.into()),
+		}
+		records.push(Record { source, arrival, id, sequence, data });
+	}
+	Ok(records)
+}
+
+/// Statistics of one side of a replay
+#[derive(Default)]
+struct ReplayStats {
+	packets: usize,
+	speakers: HashSet<u32>,
+	loss: LossCounter<u32>,
+	/// Rejected packets by error
+	errors: BTreeMap<String, usize>,
+	/// Sum and maximum of the average buffered samples per frame with audio
+	buffered_sum: usize,
+	buffered_max: usize,
+	buffered_frames: usize,
+}
+
+impl ReplayStats {
+	fn record(&mut self, record: &Record) {
+		self.packets += 1;
+		self.speakers.insert(record.id);
+		self.loss.record(record.id, record.sequence);
+	}
+
+	fn error(&mut self, error: impl std::fmt::Debug) {
+		// only the variant, not its fields
+		let name = format!("{:?}", error);
+		let name = name.split(|c: char| !c.is_alphanumeric()).next().unwrap_or_default().to_string();
+		*self.errors.entry(name).or_default() += 1;
+	}
+
+	fn buffered(&mut self, samples: Option<usize>) {
+		if let Some(samples) = samples {
+			self.buffered_sum += samples;
+			self.buffered_max = self.buffered_max.max(samples);
+			self.buffered_frames += 1;
+		}
+	}
+
+	fn print(&self, name: &str, output: &Path) {
+		let expected = self.loss.received + self.loss.lost;
+		let loss = if expected == 0 { 0.0 } else { self.loss.lost as f64 * 100.0 / expected as f64 };
+		println!("{}: {} packets of {} speakers, {} lost ({:.1}%)", name, self.packets, self.speakers.len(), self.loss.lost, loss);
+		if self.errors.is_empty() {
+			println!("  rejected: none");
+		} else {
+			let errors: Vec<String> = self.errors.iter().map(|(e, n)| format!("{} {}", e, n)).collect();
+			println!("  rejected: {}", errors.join(", "));
+		}
+		let ms = |samples: usize| (samples / 2) as f32 / (SAMPLE_RATE / 1000) as f32;
+		match self.buffered_sum.checked_div(self.buffered_frames) {
+			Some(avg) => println!("  jitter buffer: {:.0}ms average, {:.0}ms max", ms(avg), ms(self.buffered_max)),
+			None => println!("  jitter buffer: unavailable"),
+		}
+		println!("  written to {}", output.display());
+	}
+}
+
+/// `voice_bridge replay <capture> [output prefix]`
+pub fn replay_command(args: &[String]) -> Result<()> {
+	let capture = match args.first() {
+		Some(v) => PathBuf::from(v),
+		None => bail!("Usage: voice_bridge replay <capture> [output prefix]"),
+	};
+	let prefix = args.get(1).map(PathBuf::from).unwrap_or_else(|| capture.with_extension(""));
+	replay(&capture, &prefix)
+}
+
+/// Feed a capture through the jitter buffers in 20ms steps, as the bridge would, and write
+/// the output of each side to `<prefix>_discord.wav` and `<prefix>_teamspeak.wav`.
+pub fn replay(capture: &Path, prefix: &Path) -> Result<()> {
+	let records = read_records(capture)?;
+	let end = match records.last() {
+		Some(v) => v.arrival + DRAIN_TIME,
+		None => bail!("{} contains no packets", capture.display()),
+	};
+	let logger = Logger::root(slog::Discard, o!());
+	let mut discord = discord_audiohandler::AudioHandler::<u32>::new(logger.clone());
+	let mut teamspeak = TsAudioHandler::new(logger);
+	let mut discord_stats = ReplayStats::default();
+	let mut ts_stats = ReplayStats::default();
+	let mut discord_out = Vec::new();
+	let mut ts_out = Vec::new();
+
+	let mut records = records.iter().peekable();
+	let mut now = Duration::ZERO;
+	while now < end {
+		now += Duration::from_millis(FRAME_SIZE_MS as u64);
+		while let Some(record) = records.next_if(|r| r.arrival <= now) {
+			match record.source {
+				Source::Discord => {
+					discord_stats.record(record);
+					if let Err(e) = discord.handle_packet(record.id, record.sequence, record.data.clone()) {
+						discord_stats.error(e);
+					}
+				},
+				Source::Teamspeak => {
+					ts_stats.record(record);
+					let packet = match InAudioBuf::try_new(Direction::S2C, record.data.clone()) {
+						Ok(v) => v,
+						Err(e) => {
+							ts_stats.error(e);
+							continue;
+						}
+					};
+					if let Err(e) = teamspeak.handle_packet((ConnectionId(0), ClientId(record.id as u16)), packet) {
+						ts_stats.error(e);
+					}
+				},
+			}
+		}
+		discord_stats.buffered(discord.avg_buffered_samples());
+		let mut frame = [0.0; STEREO_20MS];
+		discord.fill_buffer(&mut frame);
+		discord_out.extend_from_slice(&frame);
+		let mut frame = [0.0; STEREO_20MS];
+		teamspeak.fill_buffer(&mut frame);
+		ts_out.extend_from_slice(&frame);
+	}
+
+	let discord_path = output_path(prefix, "discord");
+	write_wav(&discord_path, &discord_out)?;
+	discord_stats.print("Discord", &discord_path);
+	let ts_path = output_path(prefix, "teamspeak");
+	write_wav(&ts_path, &ts_out)?;
+	ts_stats.print("Teamspeak", &ts_path);
+	Ok(())
+}
+
+fn output_path(prefix: &Path, side: &str) -> PathBuf {
+	let mut name = prefix.as_os_str().to_owned();
+	name.push(format!("_{}.wav", side));
+	PathBuf::from(name)
+}
+
+/// Write interleaved stereo samples as 16 bit PCM
+fn write_wav(path: &Path, samples: &[f32]) -> Result<()> {
+	let mut writer = BufWriter::new(File::create(path).with_context(|| format!("Can't create {}", path.display()))?);
+	let data_len = (samples.len() * 2) as u32;
+	writer.write_all(b"RIFF")?;
+	writer.write_all(&(36 + data_len).to_le_bytes())?;
+	writer.write_all(b"WAVEfmt ")?;
+	writer.write_all(&16u32.to_le_bytes())?;
+	// PCM, stereo
+	writer.write_all(&1u16.to_le_bytes())?;
+	writer.write_all(&2u16.to_le_bytes())?;
+	writer.write_all(&(SAMPLE_RATE as u32).to_le_bytes())?;
+	writer.write_all(&(SAMPLE_RATE as u32 * 4).to_le_bytes())?;
+	writer.write_all(&4u16.to_le_bytes())?;
+	writer.write_all(&16u16.to_le_bytes())?;
+	writer.write_all(b"data")?;
+	writer.write_all(&data_len.to_le_bytes())?;
+	for sample in samples {
+		writer.write_all(&((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes())?;
+	}
+	writer.flush()?;
+	Ok(())
+}
@@ -0,0 +1,218 @@
+//! Noise suppression
+//!
+//! Spectral noise suppression per speaker: tracks the noise floor of every frequency bin
+//! and damps bins close to it with a wiener filter. Adds `FFT_SIZE` samples (~21ms) of latency.
+
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+
+use serde::Deserialize;
+
+use crate::SAMPLE_RATE;
+
+const FFT_SIZE: usize = 1024;
+const HOP: usize = FFT_SIZE / 2;
+/// Hops used to get an initial noise estimate
+const INIT_HOPS: u32 = 10;
+/// Smoothing of the a priori SNR, higher values reduce musical noise
+const DECISION_DIRECTED: f32 = 0.98;
+/// Smoothing of the power per bin used for the noise floor tracking
+const POWER_SMOOTHING: f32 = 0.8;
+/// The tracked minimum underestimates the average noise power
+const NOISE_BIAS: f32 = 2.0;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct NoiseSuppressionSettings {
+	pub discord_to_ts: Option<NoiseSuppressionConfig>,
+	pub ts_to_discord: Option<NoiseSuppressionConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct NoiseSuppressionConfig {
+	/// Maximum attenuation of noise, in dB
+	pub max_attenuation_db: f32,
+	/// How fast the noise floor may rise, in dB per second
+	pub noise_rise_db: f32,
+}
+
+impl Default for NoiseSuppressionConfig {
+	fn default() -> Self { Self { max_attenuation_db: 25.0, noise_rise_db: 3.0 } }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Complex {
+	re: f32,
+	im: f32,
+}
+
+impl Complex {
+	fn mul(self, o: Complex) -> Complex {
+		Complex { re: self.re * o.re - self.im * o.im, im: self.re * o.im + self.im * o.re }
+	}
+
+	fn norm_sqr(self) -> f32 { self.re * self.re + self.im * self.im }
+}
+
+/// Radix 2 FFT of `FFT_SIZE`
+struct Fft {
+	twiddles: Vec<Complex>,
+	reversed: Vec<usize>,
+}
+
+impl Fft {
+	fn new() -> Self {
+		let bits = FFT_SIZE.trailing_zeros();
+		Self {
+			twiddles: (0..FFT_SIZE / 2)
+				.map(|i| {
+					let angle = -2.0 * PI * i as f32 / FFT_SIZE as f32;
+					Complex { re: angle.cos(), im: angle.sin() }
+				})
+				.collect(),
+			reversed: (0..FFT_SIZE).map(|i| i.reverse_bits() >> (usize::BITS - bits)).collect(),
+		}
+	}
+
+	/// In place transform, the inverse is scaled by 1/N.
+	fn transform(&self, data: &mut [Complex], inverse: bool) {
+		for i in 0..FFT_SIZE {
+			let j = self.reversed[i];
+			if i < j {
+				data.swap(i, j);
+			}
+		}
+		let mut len = 2;
+		while len <= FFT_SIZE {
+			let step = FFT_SIZE / len;
+			for start in (0..FFT_SIZE).step_by(len) {
+				for k in 0..len / 2 {
+					let mut w = self.twiddles[k * step];
+					if inverse {
+						w.im = -w.im;
+					}
+					let a = data[start + k];
+					let b = data[start + k + len / 2].mul(w);
+					data[start + k] = Complex { re: a.re + b.re, im: a.im + b.im };
+					data[start + k + len / 2] = Complex { re: a.re - b.re, im: a.im - b.im };
+				}
+			}
+			len *= 2;
+		}
+		if inverse {
+			let scale = 1.0 / FFT_SIZE as f32;
+			for v in data.iter_mut() {
+				v.re *= scale;
+				v.im *= scale;
+			}
+		}
+	}
+}
+
+/// Noise suppression state of one speaker.
+///
+/// Both channels are packed into one complex FFT (left as real, right as imaginary part),
+/// the gain is real and symmetric, so it applies to both channels at once.
+pub struct NoiseSuppressor {
+	fft: Fft,
+	/// sqrt hann window, used for analysis and synthesis
+	window: Vec<f32>,
+	/// Minimum gain, linear
+	floor: f32,
+	/// Noise floor rise per hop, linear power
+	rise: f32,
+	input: VecDeque<Complex>,
+	output: VecDeque<Complex>,
+	overlap: Vec<Complex>,
+	/// Smoothed power and tracked noise floor, per bin
+	power: Vec<f32>,
+	noise: Vec<f32>,
+	/// Gain and a posteriori SNR of the last hop, per bin
+	last_gain: Vec<f32>,
+	last_snr: Vec<f32>,
+	hops: u32,
+	spectrum: Vec<Complex>,
+}
+
+impl NoiseSuppressor {
+	pub fn new(config: &NoiseSuppressionConfig) -> Self {
+		let bins = FFT_SIZE / 2 + 1;
+		Self {
+			fft: Fft::new(),
+			window: (0..FFT_SIZE).map(|i| (PI * i as f32 / FFT_SIZE as f32).sin()).collect(),
+			floor: 10f32.powf(-config.max_attenuation_db.abs() / 20.0),
+			rise: 10f32.powf(config.noise_rise_db / 10.0 * HOP as f32 / SAMPLE_RATE as f32),
+			input: std::iter::repeat_n(Complex::default(), FFT_SIZE - HOP).collect(),
+			// enough output for any input length, see `process`
+			output: std::iter::repeat_n(Complex::default(), HOP).collect(),
+			overlap: vec![Complex::default(); FFT_SIZE],
+			power: vec![0.0; bins],
+			noise: vec![0.0; bins],
+			last_gain: vec![1.0; bins],
+			last_snr: vec![1.0; bins],
+			hops: 0,
+			spectrum: vec![Complex::default(); FFT_SIZE],
+		}
+	}
+
+	/// Process interleaved stereo samples in place.
+	///
+	/// Every full hop of input produces a hop of output, together with the initial
+	/// padding there is always at least as much output as input.
+	pub fn process(&mut self, samples: &mut [f32]) {
+		self.input.extend(samples.chunks_exact(2).map(|s| Complex { re: s[0], im: s[1] }));
+		while self.input.len() >= FFT_SIZE {
+			self.process_hop();
+			self.input.drain(..HOP);
+		}
+		let frames = samples.len() / 2;
+		for (frame, v) in samples.chunks_exact_mut(2).zip(self.output.drain(..frames)) {
+			frame[0] = v.re;
+			frame[1] = v.im;
+		}
+	}
+
+	fn process_hop(&mut self) {
+		for (i, (out, v)) in self.spectrum.iter_mut().zip(self.input.iter()).enumerate() {
+			*out = Complex { re: v.re * self.window[i], im: v.im * self.window[i] };
+		}
+		self.fft.transform(&mut self.spectrum, false);
+
+		self.hops += 1;
+		for k in 0..=FFT_SIZE / 2 {
+			let mirror = (FFT_SIZE - k) % FFT_SIZE;
+			let power = (self.spectrum[k].norm_sqr() + self.spectrum[mirror].norm_sqr()) / 2.0 + 1e-12;
+			let smoothed = &mut self.power[k];
+			let noise = &mut self.noise[k];
+			if self.hops <= INIT_HOPS {
+				*smoothed += (power - *smoothed) / self.hops as f32;
+				*noise = *smoothed;
+			} else {
+				*smoothed = POWER_SMOOTHING * *smoothed + (1.0 - POWER_SMOOTHING) * power;
+				*noise = if *smoothed < *noise { *smoothed } else { *noise * self.rise };
+			}
+
+			let snr = power / (*noise * NOISE_BIAS);
+			let prior = DECISION_DIRECTED * self.last_gain[k] * self.last_gain[k] * self.last_snr[k]
+				+ (1.0 - DECISION_DIRECTED) * (snr - 1.0).max(0.0);
+			let gain = (prior / (1.0 + prior)).max(self.floor);
+			self.last_gain[k] = gain;
+			self.last_snr[k] = snr;
+
+			let gain = Complex { re: gain, im: 0.0 };
+			self.spectrum[k] = self.spectrum[k].mul(gain);
+			if mirror != k {
+				self.spectrum[mirror] = self.spectrum[mirror].mul(gain);
+			}
+		}
+
+		self.fft.transform(&mut self.spectrum, true);
+		for (i, v) in self.spectrum.iter().enumerate() {
+			self.overlap[i].re += v.re * self.window[i];
+			self.overlap[i].im += v.im * self.window[i];
+		}
+		self.output.extend(self.overlap.drain(..HOP));
+		self.overlap.resize(FFT_SIZE, Complex::default());
+	}
+}
@@ -1,21 +1,32 @@
 //! Discord handler
 
 use anyhow::bail;
-use serenity::builder::CreateApplicationCommand;
+use serenity::builder::{CreateApplicationCommand, CreateApplicationCommandOption, CreateApplicationCommands};
 use serenity::model::application::command::Command;
 use serenity::model::prelude::command::CommandOptionType;
 use serenity::model::application::interaction::{Interaction, InteractionResponseType};
-use serenity::model::id::GuildId;
-use serenity::model::prelude::interaction::application_command::{CommandDataOption, CommandDataOptionValue, ApplicationCommandInteraction};
+use serenity::model::gateway::Activity;
+use serenity::model::user::OnlineStatus;
+use serenity::model::prelude::interaction::application_command::{CommandDataOptionValue, ApplicationCommandInteraction};
+use serenity::model::prelude::interaction::autocomplete::AutocompleteInteraction;
 // This trait adds the `register_songbird` and `register_songbird_with` methods
 // to the client builder below, making it easy to install this voice client.
 // The voice client can be retrieved in any command using `songbird::get(ctx).await`.
 use songbird::input::Input;
 
-use serenity::prelude::*;
-
 // Import the `Context` to handle commands.
 use serenity::client::Context;
+use serenity::http::Http;
+use serenity::model::channel::{Channel, ChannelType};
+use serenity::model::id::{ChannelId, GuildId, UserId};
+use serenity::model::voice::VoiceState;
+use serenity::model::permissions::Permissions;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
 
 use serenity::{
     async_trait,
@@ -23,7 +34,7 @@ use serenity::{
     framework::{
         standard::{
             Args, CommandResult,
-            macros::{command, group},
+            macros::{command, group, hook},
         },
     },
     model::{channel::Message, gateway::Ready},
@@ -39,17 +50,85 @@ use songbird::{
     EventHandler as VoiceEventHandler,
 };
 
-use crate::ListenerHolder;
+use crate::access::{AccessEntry, AccessHandle};
+use crate::idle::{Idle, IdleAction, IdleConfig};
+use crate::presence::{Presence, PresenceConfig};
+use crate::capture::CaptureHandle;
+use crate::discord_puppet::DiscordPuppetsHandle;
+use crate::recorder::{Source, Track};
+use crate::status::{format_duration, Direction, DirectionStats, Paused, MAX_PAUSE_MINUTES};
+use crate::roster::RosterHandle;
+use crate::state::{StateHandle, UserSettings, MAX_USER_VOLUME};
+use crate::{AccessHolder, CaptureHolder, DiscordPuppetsHolder, FollowHolder, ListenerHolder, PermissionsHolder, RecorderHolder, ReplayHolder, RosterHolder, SoundboardHolder, SpeakersHolder, StateHolder, StatusHolder, TsCommand, TsCommandHolder};
+
+pub(crate) struct Handler {
+    /// Guilds to register slash commands in, global registration if empty
+    pub guilds: Vec<GuildId>,
+    /// Text channel for the pinned roster
+    pub roster_channel: Option<ChannelId>,
+    /// Whether the roster task runs, ready is sent again on reconnects
+    pub roster_started: AtomicBool,
+    /// Teamspeak state in our activity and a channel topic
+    pub presence: PresenceConfig,
+    /// Whether the presence task runs
+    pub presence_started: AtomicBool,
+    /// Leaving voice while no one is around
+    pub idle: IdleConfig,
+    /// Whether the idle task runs
+    pub idle_started: AtomicBool,
+}
 
-pub(crate) struct Handler;
+/// How often the pinned roster is updated at most
+const ROSTER_INTERVAL: Duration = Duration::from_secs(10);
+/// How often teamspeak speakers are sampled for the presence
+const PRESENCE_INTERVAL: Duration = Duration::from_secs(2);
+/// How often the occupancy of both sides is checked for leaving and joining
+const IDLE_INTERVAL: Duration = Duration::from_secs(2);
 
 #[async_trait]
 impl EventHandler for Handler {
     async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        if let Interaction::Autocomplete(autocomplete) = &interaction {
+            if let Err(why) = handle_autocomplete(&ctx, autocomplete).await {
+                println!("Failed to autocomplete: {}", why);
+            }
+            return;
+        }
         if let Interaction::ApplicationCommand(command) = interaction {
             println!("Received command interaction: {:#?}", command);
+            if !is_command_allowed(&ctx, &command).await {
+                if let Err(why) = command
+                    .create_interaction_response(&ctx.http, |response| {
+                        response.kind(InteractionResponseType::ChannelMessageWithSource)
+                            .interaction_response_data(|message| message.content(NOT_ALLOWED).ephemeral(true))
+                    })
+                    .await
+                {
+                    println!("Cannot respond to slash command: {}", why);
+                }
+                return;
+            }
             let result: Result<(), anyhow::Error> = match command.data.name.as_str() {
                 "join_voice" => handle_join(&ctx,&command).await,
+                "record" => handle_record(&ctx,&command).await,
+                "clip" => handle_clip(&ctx,&command).await,
+                "play" => handle_play(&ctx,&command).await,
+                "stop" => handle_stop(&ctx,&command).await,
+                "skip" => handle_skip(&ctx,&command).await,
+                "queue" => handle_queue(&ctx,&command).await,
+                "access" => handle_access(&ctx,&command).await,
+                "bridge" => handle_bridge(&ctx,&command).await,
+                "ts_who" => handle_ts_who(&ctx,&command).await,
+                "ts_channel" => handle_ts_channel(&ctx,&command).await,
+                "follow" => handle_follow(&ctx,&command).await,
+                "speaker" => handle_speaker(&ctx,&command).await,
+                "settings" => handle_settings(&ctx,&command).await,
+                "leave" => handle_leave(&ctx,&command).await,
+                "mute" => handle_mute(&ctx,&command,true).await,
+                "unmute" => handle_mute(&ctx,&command,false).await,
+                "deafen" => handle_deafen(&ctx,&command,true).await,
+                "undeafen" => handle_deafen(&ctx,&command,false).await,
+                "ping" => respond(&ctx,&command,"Pong!".to_string()).await,
                 _ => Err(anyhow::Error::msg("not implemented :(")),
             };
 
@@ -78,55 +157,598 @@ impl EventHandler for Handler {
 
     async fn ready(&self, ctx: Context, ready: Ready) {
         println!("{} is connected!", ready.user.name);
-        Command::create_global_application_command(&ctx.http, |command| {
-            register_join(command)
-        })
-        .await.expect("Failed creating commands");
+        let clip_seconds = {
+            let data_read = ctx.data.read().await;
+            let replay = data_read.get::<ReplayHolder>().expect("Expected replay buffer in TypeMap.");
+            let seconds = replay.lock().expect("Can't lock replay buffer!").seconds();
+            seconds
+        };
+        // setting the commands replaces all existing ones, which removes stale commands
+        if self.guilds.is_empty() {
+            Command::set_global_application_commands(&ctx.http, |commands| register_commands(commands, clip_seconds))
+                .await.expect("Failed creating commands");
+            for guild in &ready.guilds {
+                clear_guild_commands(&ctx, guild.id).await;
+            }
+        } else {
+            for guild_id in &self.guilds {
+                guild_id.set_application_commands(&ctx.http, |commands| register_commands(commands, clip_seconds))
+                    .await.expect("Failed creating guild commands");
+            }
+            // global commands would show up twice
+            Command::set_global_application_commands(&ctx.http, |commands| commands)
+                .await.expect("Failed removing global commands");
+            for guild in ready.guilds.iter().filter(|g| !self.guilds.contains(&g.id)) {
+                clear_guild_commands(&ctx, guild.id).await;
+            }
+        }
+        if let Some(channel) = self.roster_channel {
+            if !self.roster_started.swap(true, Ordering::SeqCst) {
+                let roster = {
+                    let data_read = ctx.data.read().await;
+                    data_read.get::<RosterHolder>().expect("Expected roster in TypeMap.").clone()
+                };
+                tokio::spawn(run_roster_message(ctx.http.clone(), roster, channel, ready.user.id));
+            }
+        }
+        if (self.presence.activity || self.presence.topic_channel.is_some()) && !self.presence_started.swap(true, Ordering::SeqCst) {
+            tokio::spawn(run_presence(ctx.clone(), Presence::new(self.presence.clone()), self.presence.topic_channel.map(ChannelId)));
+        }
+        if let Some(idle) = Idle::new(&self.idle) {
+            if !self.idle_started.swap(true, Ordering::SeqCst) {
+                tokio::spawn(run_idle(ctx.clone(), idle, self.idle.teamspeak_away.clone()));
+            }
+        }
+    }
+
+    async fn voice_state_update(&self, ctx: Context, _old: Option<VoiceState>, new: VoiceState) {
+        if let Some(guild_id) = new.guild_id {
+            update_discord_roster(&ctx, guild_id, new.user_id).await;
+            follow_user(&ctx, guild_id, &new).await;
+            // moved to the audience of a stage, without a pending request to speak
+            if new.user_id == ctx.cache.current_user_id() && new.suppress && new.request_to_speak_timestamp.is_none() {
+                if let Some(channel) = new.channel_id {
+                    become_speaker(&ctx, channel).await;
+                }
+            }
+        }
     }
 }
 
-#[group]
-#[commands(deafen, leave, mute, play, ping, undeafen, unmute)]
-pub struct General;
+/// Update the discord side of the roster with the members of our voice channel in this guild.
+async fn update_discord_roster(ctx: &Context, guild_id: GuildId, changed: UserId) {
+    let guild = match ctx.cache.guild(guild_id) {
+        Some(v) => v,
+        None => return,
+    };
+    let own_id = ctx.cache.current_user_id();
+    let channel = match guild.voice_states.get(&own_id).and_then(|s| s.channel_id) {
+        Some(v) => v,
+        None => {
+            // we may be in voice in another guild, only clear the roster when we left
+            if changed == own_id {
+                let roster = {
+                    let data_read = ctx.data.read().await;
+                    data_read.get::<RosterHolder>().expect("Expected roster in TypeMap.").clone()
+                };
+                roster.lock().expect("Can't lock roster!").set_discord(Vec::new());
+            }
+            return;
+        }
+    };
+    let (roster, puppets) = {
+        let data_read = ctx.data.read().await;
+        let roster = data_read.get::<RosterHolder>().expect("Expected roster in TypeMap.").clone();
+        let puppets = data_read.get::<DiscordPuppetsHolder>().expect("Expected discord puppets in TypeMap.").clone();
+        (roster, puppets)
+    };
+    // bots like our puppets aren't people in the channel
+    let members = {
+        let puppets = puppets.lock().expect("Can't lock discord puppets!");
+        guild.voice_states.values()
+            .filter(|s| s.channel_id == Some(channel) && s.user_id != own_id && !puppets.is_puppet_user(s.user_id.0))
+            .filter_map(|s| match guild.members.get(&s.user_id).or(s.member.as_ref()) {
+                Some(member) if member.user.bot => None,
+                Some(member) => Some(member.display_name().into_owned()),
+                None => Some(s.user_id.to_string()),
+            })
+            .collect()
+    };
+    roster.lock().expect("Can't lock roster!").set_discord(members);
+}
 
-#[command]
-#[only_in(guilds)]
-async fn deafen(ctx: &Context, msg: &Message) -> CommandResult {
-    let guild = msg.guild(&ctx.cache).expect("No guild found!");
-    let guild_id = guild.id;
+/// Move with the followed user of this guild, stops following when the user disconnects.
+async fn follow_user(ctx: &Context, guild_id: GuildId, state: &VoiceState) {
+    let (follow, store) = {
+        let data_read = ctx.data.read().await;
+        let follow = data_read.get::<FollowHolder>().expect("Expected follow targets in TypeMap.").clone();
+        let store = data_read.get::<StateHolder>().expect("Expected state in TypeMap.").clone();
+        (follow, store)
+    };
+    {
+        let mut follow = follow.lock().expect("Can't lock follow targets!");
+        if follow.get(&guild_id) != Some(&state.user_id) {
+            return;
+        }
+        if state.channel_id.is_none() {
+            println!("Followed user {} disconnected, stopped following", state.user_id);
+            follow.remove(&guild_id);
+            let result = store.lock().expect("Can't lock state!").update(|s| {
+                s.discord_follow.remove(&guild_id.to_string());
+            });
+            if let Err(e) = result {
+                println!("Failed to save state: {}", e);
+            }
+            return;
+        }
+    }
+    if let Some(channel) = state.channel_id {
+        let current = match get_call(ctx, guild_id).await {
+            Ok(call) => call.lock().await.current_channel(),
+            Err(_) => None,
+        };
+        if current.map(|c| c.0) != Some(channel.0) {
+            if let Err(e) = join_voice(ctx, guild_id, channel).await {
+                println!("Failed to follow into {}: {}", channel, e);
+            }
+        }
+    }
+}
 
-    let manager = songbird::get(ctx).await
-        .expect("Songbird Voice client placed in at initialisation.").clone();
+/// Keep a pinned roster message in `channel` up to date, reuses our pinned message of earlier runs.
+async fn run_roster_message(http: Arc<Http>, roster: RosterHandle, channel: ChannelId, own_id: UserId) {
+    let mut message = match channel.pins(&http).await {
+        Ok(pins) => pins.into_iter().find(|m| m.author.id == own_id).map(|m| m.id),
+        Err(e) => {
+            println!("Failed fetching pinned messages of {}: {}", channel, e);
+            None
+        }
+    };
+    let mut generation = None;
+    let mut interval = tokio::time::interval(ROSTER_INTERVAL);
+    loop {
+        interval.tick().await;
+        let (current, content) = {
+            let roster = roster.lock().expect("Can't lock roster!");
+            (roster.generation(), roster.format_pinned())
+        };
+        if generation == Some(current) {
+            continue;
+        }
+        let result = match message {
+            Some(id) => channel.edit_message(&http, id, |m| m.content(&content)).await.map(|_| ()),
+            None => match channel.send_message(&http, |m| m.content(&content)).await {
+                Ok(sent) => {
+                    message = Some(sent.id);
+                    channel.pin(&http, sent.id).await
+                },
+                Err(e) => Err(e),
+            },
+        };
+        match result {
+            Ok(()) => generation = Some(current),
+            Err(e) => println!("Failed updating roster message: {}", e),
+        }
+    }
+}
 
-    let handler_lock = match manager.get(guild_id) {
-        Some(handler) => handler,
-        None => {
-            check_msg(msg.reply(ctx, "Not in a voice channel").await);
+/// Mirror the teamspeak channel into our activity and the topic of `topic_channel`.
+async fn run_presence(ctx: Context, mut presence: Presence, topic_channel: Option<ChannelId>) {
+    let (roster, pipeline, recorder) = {
+        let data_read = ctx.data.read().await;
+        let roster = data_read.get::<RosterHolder>().expect("Expected roster in TypeMap.").clone();
+        let (pipeline, _) = data_read.get::<ListenerHolder>().expect("Expected voice pipelines in TypeMap.").clone();
+        let recorder = data_read.get::<RecorderHolder>().expect("Expected recorder in TypeMap.").clone();
+        (roster, pipeline, recorder)
+    };
+    let mut interval = tokio::time::interval(PRESENCE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let talking = pipeline.talking_clients();
+        let recording = recorder.lock().expect("Can't lock recorder!").is_recording();
+        let (activity, topic) = {
+            let roster = roster.lock().expect("Can't lock roster!");
+            let members = roster.teamspeak();
+            presence.heard(members.iter().filter(|m| talking.contains(&m.id)).map(|m| m.name.clone()));
+            (presence.activity(members.len(), recording), presence.topic(roster.ts_channel(), members.len()))
+        };
+        if let Some(activity) = activity {
+            ctx.set_presence(Some(Activity::playing(activity)), OnlineStatus::Online).await;
+        }
+        if let (Some(topic), Some(channel)) = (topic, topic_channel) {
+            if let Err(e) = channel.edit(&ctx.http, |c| c.topic(topic)).await {
+                println!("Failed updating topic of {}: {}", channel, e);
+            }
+        }
+    }
+}
 
-            return Ok(());
+/// Leave voice while both sides are empty and join again when someone shows up.
+async fn run_idle(ctx: Context, mut idle: Idle, away: Option<String>) {
+    let (roster, ts_commands) = {
+        let data_read = ctx.data.read().await;
+        let roster = data_read.get::<RosterHolder>().expect("Expected roster in TypeMap.").clone();
+        let ts_commands = data_read.get::<TsCommandHolder>().expect("Expected ts commands in TypeMap.").clone();
+        (roster, ts_commands)
+    };
+    let mut interval = tokio::time::interval(IDLE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let own_id = ctx.cache.current_user_id();
+        let bridged = ctx.cache.guilds().into_iter().find_map(|guild_id| {
+            let guild = ctx.cache.guild(guild_id)?;
+            let channel = guild.voice_states.get(&own_id)?.channel_id?;
+            Some((guild_id, channel))
+        });
+        let (guild_id, channel) = match idle.watched(bridged) {
+            Some(v) => v,
+            None => continue,
+        };
+        let in_discord = match ctx.cache.guild(guild_id) {
+            // bots like the puppets don't count
+            Some(guild) => guild.voice_states.values()
+                .filter(|s| s.channel_id == Some(channel))
+                .any(|s| !guild.members.get(&s.user_id).or(s.member.as_ref()).map(|m| m.user.bot).unwrap_or_default()),
+            None => false,
+        };
+        let in_teamspeak = !roster.lock().expect("Can't lock roster!").teamspeak().is_empty();
+        match idle.update(bridged, in_discord || in_teamspeak) {
+            Some(IdleAction::Leave(guild_id)) => {
+                println!("No one on either side, leaving voice");
+                if let Err(e) = leave_voice(&ctx, guild_id).await {
+                    println!("Failed to leave voice: {}", e);
+                }
+                if away.is_some() {
+                    let _ = ts_commands.send(TsCommand::Away(away.clone()));
+                }
+            },
+            Some(IdleAction::Join(guild_id, channel)) => {
+                println!("Someone showed up, joining {} again", channel);
+                if let Err(e) = join_voice(&ctx, guild_id, channel).await {
+                    println!("Failed to join {} again: {}", channel, e);
+                }
+                if away.is_some() {
+                    let _ = ts_commands.send(TsCommand::Away(None));
+                }
+            },
+            None => (),
+        }
+    }
+}
+
+/// `clip_seconds` is the length of the replay buffer, the longest possible clip
+fn register_commands(commands: &mut CreateApplicationCommands, clip_seconds: usize) -> &mut CreateApplicationCommands {
+    commands
+        .create_application_command(|command| register_join(command))
+        .create_application_command(|command| command.name("leave").description("Leave the voice channel"))
+        .create_application_command(|command| command.name("mute").description("Mute the bridge in discord"))
+        .create_application_command(|command| command.name("unmute").description("Unmute the bridge in discord"))
+        .create_application_command(|command| command.name("deafen").description("Stop bridging discord to teamspeak"))
+        .create_application_command(|command| command.name("undeafen").description("Resume bridging discord to teamspeak"))
+        .create_application_command(|command| command.name("ping").description("Check if the bot is alive"))
+        .create_application_command(|command| register_record(command))
+        .create_application_command(|command| register_clip(command, clip_seconds))
+        .create_application_command(|command| register_play(command))
+        .create_application_command(|command| command.name("stop").description("Stop the soundboard and clear its queue"))
+        .create_application_command(|command| command.name("skip").description("Skip the current soundboard clip"))
+        .create_application_command(|command| command.name("queue").description("Show the soundboard queue"))
+        .create_application_command(|command| register_access(command))
+        .create_application_command(|command| register_bridge(command))
+        .create_application_command(|command| command.name("ts_who").description("List the teamspeak channel of the bridge"))
+        .create_application_command(|command| register_ts_channel(command))
+        .create_application_command(|command| register_follow(command))
+        .create_application_command(|command| register_speaker(command))
+        .create_application_command(|command| register_settings(command))
+}
+
+/// Remove our commands of a guild we don't register commands in anymore.
+async fn clear_guild_commands(ctx: &Context, guild_id: GuildId) {
+    match guild_id.get_application_commands(&ctx.http).await {
+        Ok(commands) if !commands.is_empty() => {
+            if let Err(e) = guild_id.set_application_commands(&ctx.http, |commands| commands).await {
+                println!("Failed removing stale commands of {}: {}", guild_id, e);
+            }
         },
+        Ok(_) => (),
+        Err(e) => println!("Failed fetching commands of {}: {}", guild_id, e),
+    }
+}
+
+/// Respond with a message visible for everyone
+async fn respond(ctx: &Context, interaction: &ApplicationCommandInteraction, message: String) -> anyhow::Result<()> {
+    interaction.create_interaction_response(&ctx.http, |response| {
+        response.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|data| data.content(message))
+    })
+    .await?;
+    Ok(())
+}
+
+fn interaction_guild(interaction: &ApplicationCommandInteraction) -> anyhow::Result<GuildId> {
+    match interaction.guild_id {
+        Some(v) => Ok(v),
+        None => bail!("Only available in servers!"),
+    }
+}
+
+async fn handle_ts_who(ctx: &Context, interaction: &ApplicationCommandInteraction) -> anyhow::Result<()> {
+    let (roster, pipeline) = {
+        let data_read = ctx.data.read().await;
+        let roster = data_read.get::<RosterHolder>().expect("Expected roster in TypeMap.").clone();
+        let (pipeline, _) = data_read.get::<ListenerHolder>().expect("Expected voice pipelines in TypeMap.").clone();
+        (roster, pipeline)
     };
+    let talking = pipeline.talking_clients();
+    let message = roster.lock().expect("Can't lock roster!").format_teamspeak(&talking);
+    respond(ctx, interaction, message).await
+}
 
-    let mut handler = handler_lock.lock().await;
+fn register_ts_channel(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command.name("ts_channel").description("Move the bridge to another teamspeak channel")
+        .create_option(|option|
+            option.name("channel").description("teamspeak channel")
+            .kind(CommandOptionType::String).set_autocomplete(true).required(true))
+        .create_option(|option|
+            option.name("password").description("channel password")
+            .kind(CommandOptionType::String).required(false))
+}
 
-    if handler.is_deaf() {
-        check_msg(msg.channel_id.say(&ctx.http, "Already deafened").await);
-    } else {
-        if let Err(e) = handler.deafen(true).await {
-            check_msg(msg.channel_id.say(&ctx.http, format!("Failed: {:?}", e)).await);
+async fn handle_ts_channel(ctx: &Context, interaction: &ApplicationCommandInteraction) -> anyhow::Result<()> {
+    let mut channel = None;
+    let mut password = None;
+    for option in &interaction.data.options {
+        match (option.name.as_str(), &option.resolved) {
+            ("channel", Some(CommandDataOptionValue::String(v))) => channel = Some(v.clone()),
+            ("password", Some(CommandDataOptionValue::String(v))) => password = Some(v.clone()),
+            _ => bail!("Unexpected argument {}!", option.name),
         }
+    }
+    let channel = match channel {
+        Some(v) => v,
+        None => bail!("Expected a channel!"),
+    };
+    // autocompleted choices are IDs, typed ones are paths
+    let target = {
+        let roster = get_roster(ctx).await;
+        let roster = roster.lock().expect("Can't lock roster!");
+        roster.ts_channels().iter()
+            .find(|c| c.id.to_string() == channel || c.path.eq_ignore_ascii_case(&channel))
+            .cloned()
+    };
+    let target = match target {
+        Some(v) => v,
+        None => bail!("Unknown teamspeak channel {}", channel),
+    };
+    if target.has_password && password.is_none() {
+        bail!("{} requires a password", target.path);
+    }
+
+    let sender = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<TsCommandHolder>().expect("Expected ts commands in TypeMap.").clone()
+    };
+    // the move takes longer than discord waits for a response
+    interaction.create_interaction_response(&ctx.http, |response| {
+        response.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+            .interaction_response_data(|data| data.ephemeral(true))
+    })
+    .await?;
+    let (reply, result) = oneshot::channel();
+    sender.send(TsCommand::MoveChannel { id: target.id, password, reply })?;
+    let message = result.await??;
+    interaction.edit_original_interaction_response(&ctx.http, |response| response.content(message)).await?;
+    Ok(())
+}
 
-        check_msg(msg.channel_id.say(&ctx.http, "Deafened").await);
+fn register_follow(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command.name("follow").description("Let the bridge follow someone between channels")
+        .create_option(|option|
+            option.name("discord").description("Follow a discord user").kind(CommandOptionType::SubCommand)
+            .create_sub_option(|o| o.name("user").description("discord user").kind(CommandOptionType::User).required(true)))
+        .create_option(|option|
+            option.name("teamspeak").description("Follow a teamspeak client").kind(CommandOptionType::SubCommand)
+            .create_sub_option(|o| o.name("client").description("teamspeak nickname").kind(CommandOptionType::String).required(true)))
+        .create_option(|option|
+            option.name("stop").description("Stop following on both sides").kind(CommandOptionType::SubCommand))
+}
+
+async fn handle_follow(ctx: &Context, interaction: &ApplicationCommandInteraction) -> anyhow::Result<()> {
+    let guild_id = interaction_guild(interaction)?;
+    let subcommand = match interaction.data.options.first() {
+        Some(v) => v,
+        None => bail!("Expected subcommand!"),
+    };
+    let mut user = None;
+    let mut client = None;
+    for option in &subcommand.options {
+        match (option.name.as_str(), &option.resolved) {
+            ("user", Some(CommandDataOptionValue::User(v, _))) => user = Some(v.id),
+            ("client", Some(CommandDataOptionValue::String(v))) => client = Some(v.clone()),
+            _ => bail!("Unexpected argument {}!", option.name),
+        }
     }
+    let (follow, ts_commands) = {
+        let data_read = ctx.data.read().await;
+        let follow = data_read.get::<FollowHolder>().expect("Expected follow targets in TypeMap.").clone();
+        let ts_commands = data_read.get::<TsCommandHolder>().expect("Expected ts commands in TypeMap.").clone();
+        (follow, ts_commands)
+    };
+    // joining can take longer than discord waits for a response
+    interaction.create_interaction_response(&ctx.http, |response| {
+        response.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+    })
+    .await?;
 
+    let message = match subcommand.name.as_str() {
+        "discord" => {
+            let user = match user {
+                Some(v) => v,
+                None => bail!("Expected a user!"),
+            };
+            follow.lock().expect("Can't lock follow targets!").insert(guild_id, user);
+            get_store(ctx).await.lock().expect("Can't lock state!").update(|s| {
+                s.discord_follow.insert(guild_id.to_string(), user.0);
+            })?;
+            let channel = ctx.cache.guild(guild_id)
+                .and_then(|g| g.voice_states.get(&user).and_then(|s| s.channel_id));
+            if let Some(channel) = channel {
+                join_voice(ctx, guild_id, channel).await?;
+            }
+            format!("Following <@{}> in discord", user)
+        },
+        "teamspeak" => {
+            let (reply, result) = oneshot::channel();
+            ts_commands.send(TsCommand::Follow { name: client, reply })?;
+            result.await??
+        },
+        "stop" => {
+            follow.lock().expect("Can't lock follow targets!").remove(&guild_id);
+            get_store(ctx).await.lock().expect("Can't lock state!").update(|s| {
+                s.discord_follow.remove(&guild_id.to_string());
+            })?;
+            let (reply, result) = oneshot::channel();
+            ts_commands.send(TsCommand::Follow { name: None, reply })?;
+            result.await??;
+            "Stopped following".to_string()
+        },
+        _ => bail!("Unknown subcommand {}!", subcommand.name),
+    };
+    interaction.edit_original_interaction_response(&ctx.http, |response| response.content(message)).await?;
     Ok(())
 }
 
+async fn handle_leave(ctx: &Context, interaction: &ApplicationCommandInteraction) -> anyhow::Result<()> {
+    let message = leave_voice(ctx, interaction_guild(interaction)?).await?;
+    respond(ctx, interaction, message).await
+}
+
+async fn handle_mute(ctx: &Context, interaction: &ApplicationCommandInteraction, mute: bool) -> anyhow::Result<()> {
+    let message = set_mute(ctx, interaction_guild(interaction)?, mute).await?;
+    respond(ctx, interaction, message).await
+}
+
+async fn handle_deafen(ctx: &Context, interaction: &ApplicationCommandInteraction, deafen: bool) -> anyhow::Result<()> {
+    let message = set_deafen(ctx, interaction_guild(interaction)?, deafen).await?;
+    respond(ctx, interaction, message).await
+}
+
+async fn get_call(ctx: &Context, guild_id: GuildId) -> anyhow::Result<Arc<tokio::sync::Mutex<songbird::Call>>> {
+    let manager = songbird::get(ctx).await
+        .expect("Songbird Voice client placed in at initialisation.").clone();
+    match manager.get(guild_id) {
+        Some(v) => Ok(v),
+        None => bail!("Not in a voice channel"),
+    }
+}
+
+async fn leave_voice(ctx: &Context, guild_id: GuildId) -> anyhow::Result<String> {
+    let manager = songbird::get(ctx).await
+        .expect("Songbird Voice client placed in at initialisation.").clone();
+    if manager.get(guild_id).is_none() {
+        bail!("Not in a voice channel");
+    }
+    manager.remove(guild_id).await?;
+    {
+        let data_read = ctx.data.read().await;
+        let puppets = data_read.get::<DiscordPuppetsHolder>().expect("Expected discord puppets in TypeMap.");
+        puppets.lock().expect("Can't lock discord puppets!").set_guild(None);
+    }
+    Ok("Left voice channel".to_string())
+}
+
+async fn set_mute(ctx: &Context, guild_id: GuildId, mute: bool) -> anyhow::Result<String> {
+    let call = get_call(ctx, guild_id).await?;
+    let mut handler = call.lock().await;
+    if handler.is_mute() == mute {
+        return Ok(if mute { "Already muted" } else { "Not muted" }.to_string());
+    }
+    handler.mute(mute).await?;
+    Ok(if mute { "Now muted" } else { "Unmuted" }.to_string())
+}
+
+async fn set_deafen(ctx: &Context, guild_id: GuildId, deafen: bool) -> anyhow::Result<String> {
+    let call = get_call(ctx, guild_id).await?;
+    let mut handler = call.lock().await;
+    if handler.is_deaf() == deafen {
+        return Ok(if deafen { "Already deafened" } else { "Not deafened" }.to_string());
+    }
+    handler.deafen(deafen).await?;
+    Ok(if deafen { "Deafened" } else { "Undeafened" }.to_string())
+}
+
+/// Play a video or audio url into the discord voice channel.
+async fn play_url(ctx: &Context, guild_id: GuildId, url: &str, volume: f32) -> anyhow::Result<String> {
+    if !url.starts_with("http") {
+        bail!("Must provide a valid URL");
+    }
+    let call = get_call(ctx, guild_id).await?;
+    let source = match songbird::ytdl(url).await {
+        Ok(source) => source,
+        Err(why) => {
+            println!("Err starting source: {:?}", why);
+            bail!("Error sourcing ffmpeg");
+        },
+    };
+    let track = call.lock().await.play_source(source);
+    track.set_volume(volume)?;
+    Ok("Playing song".to_string())
+}
+
+const NOT_ALLOWED: &str = "You are not allowed to use this command.";
+
+async fn get_permissions(ctx: &Context) -> Arc<crate::permissions::CommandPermissions> {
+    let data_read = ctx.data.read().await;
+    data_read.get::<PermissionsHolder>().expect("Expected permissions in TypeMap.").clone()
+}
+
+/// Check the command permissions of the invoking member, discord sends their permissions with the interaction.
+async fn is_command_allowed(ctx: &Context, interaction: &ApplicationCommandInteraction) -> bool {
+    let permissions = get_permissions(ctx).await;
+    let command = interaction.data.name.as_str();
+    let subcommand = interaction.data.options.first()
+        .filter(|o| matches!(o.kind, CommandOptionType::SubCommand | CommandOptionType::SubCommandGroup))
+        .map(|o| o.name.as_str());
+    let member = match &interaction.member {
+        Some(v) => v,
+        // not in a guild, only commands open to everyone
+        None => return permissions.is_allowed(command, subcommand, &[], Permissions::empty()),
+    };
+    let member_permissions = member.permissions.unwrap_or_else(|| member.permissions(&ctx.cache).unwrap_or_default());
+    permissions.is_allowed(command, subcommand, &member.roles, member_permissions)
+}
+
+/// Permission check for prefix commands
+#[hook]
+pub async fn check_permission(ctx: &Context, msg: &Message, command_name: &str) -> bool {
+    let permissions = get_permissions(ctx).await;
+    let allowed = if msg.guild_id.is_some() {
+        match msg.member(ctx).await {
+            Ok(member) => {
+                let member_permissions = member.permissions(&ctx.cache).unwrap_or_default();
+                permissions.is_allowed(command_name, None, &member.roles, member_permissions)
+            },
+            Err(_) => false,
+        }
+    } else {
+        // direct message, only commands open to everyone
+        permissions.is_allowed(command_name, None, &[], Permissions::empty())
+    };
+    if allowed {
+        return true;
+    }
+    check_msg(msg.reply(ctx, NOT_ALLOWED).await);
+    false
+}
+
+#[group]
+#[commands(deafen, leave, mute, play, ping, undeafen, unmute)]
+pub struct General;
+
 fn register_join(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
     command.name("join_voice").description("Join voice channel")
         .create_option(|option|
-            option.name("channel").description("channel to join")
-            .kind(CommandOptionType::Channel).required(true))
+            option.name("channel").description("voice or stage channel to join")
+            .kind(CommandOptionType::Channel).channel_types(&[ChannelType::Voice, ChannelType::Stage]).required(true))
 }
 
 async fn handle_join(ctx: &Context ,interaction: &ApplicationCommandInteraction) -> anyhow::Result<()> {
@@ -135,16 +757,21 @@ async fn handle_join(ctx: &Context ,interaction: &ApplicationCommandInteraction)
         None => bail!("Command can't be used outside of servers!"),
     };
     let option = interaction.data.options
-        .get(0)
+        .first()
         .expect("Expected user option")
         .resolved
         .as_ref()
         .expect("Expected user object");
 
     let connect_to = match option {
-        CommandDataOptionValue::Channel(part_chan) => part_chan.id,
+        CommandDataOptionValue::Channel(part_chan) => part_chan,
         _ => bail!("Expected channel argument!"),
     };
+    // commands registered by older versions allow any channel
+    if !matches!(connect_to.kind, ChannelType::Voice | ChannelType::Stage) {
+        bail!("<#{}> is not a voice or stage channel!", connect_to.id);
+    }
+    let connect_to = connect_to.id;
     // let guild = msg.guild(&ctx.cache).expect("No guild found!");
     // let guild_id = guild.id;
 
@@ -166,13 +793,63 @@ async fn handle_join(ctx: &Context ,interaction: &ApplicationCommandInteraction)
         .interaction_response_data(|f| f.ephemeral(true))
         
     })
-    .await;
+    .await?;
+
+    join_voice(ctx, guild_id, connect_to).await?;
 
+    //     check_msg(msg.channel_id.say(&ctx.http, &format!("Joined {}", connect_to.mention())).await);
+    // } else {
+    //     check_msg(msg.channel_id.say(&ctx.http, "Error joining the channel").await);
+    // }
+    println!("joined");
+    interaction.edit_original_interaction_response(&ctx.http, |response| {
+        // response.kind(InteractionResponseType::ChannelMessageWithSource).content("Joined")
+        response.content("Joined")  
+    })
+    .await?;
+    // interaction.create_followup_message(&ctx.http, |response| {
+    //     response.content("Joined")
+    // }).await?;
+    Ok(())
+}
+
+/// Speak in stage channels, directly with the mute members permission or by requesting to speak.
+async fn become_speaker(ctx: &Context, channel_id: ChannelId) {
+    let channel = match channel_id.to_channel(ctx).await {
+        Ok(Channel::Guild(v)) if v.kind == ChannelType::Stage => v,
+        Ok(_) => return,
+        Err(e) => {
+            println!("Failed fetching channel {}: {}", channel_id, e);
+            return;
+        }
+    };
+    if channel.edit_own_voice_state(&ctx.http, |s| s.suppress(false)).await.is_ok() {
+        return;
+    }
+    match channel.edit_own_voice_state(&ctx.http, |s| s.request_to_speak(true)).await {
+        Ok(()) => println!("Requested to speak in stage {}", channel_id),
+        Err(e) => println!("Failed to request to speak in stage {}: {}", channel_id, e),
+    }
+}
+
+/// Join a voice channel and start bridging, only moves when already in a channel of this guild.
+async fn join_voice(ctx: &Context, guild_id: GuildId, connect_to: ChannelId) -> anyhow::Result<()> {
     let manager = songbird::get(ctx).await
         .expect("Songbird Voice client placed in at initialisation.").clone();
+    // the call keeps its pipeline and events when moving
+    let bridged = manager.get(guild_id).is_some();
         
     let (handler_lock, conn_result) = manager.join(guild_id, connect_to).await;
     conn_result?;
+    {
+        let data_read = ctx.data.read().await;
+        let puppets = data_read.get::<DiscordPuppetsHolder>().expect("Expected discord puppets in TypeMap.");
+        puppets.lock().expect("Can't lock discord puppets!").set_guild(Some(guild_id));
+    }
+    become_speaker(ctx, connect_to).await;
+    if bridged {
+        return Ok(());
+    }
 
     // if let Ok(_) = conn_result {
         // NOTE: this skips listening for the actual connection result.
@@ -184,207 +861,755 @@ async fn handle_join(ctx: &Context ,interaction: &ApplicationCommandInteraction)
             channel = chan;
             ts_buffer = ts_buf;
         }
+        let (recorder, capture, access, speakers, puppets) = {
+            let data_read = ctx.data.read().await;
+            let recorder = data_read.get::<RecorderHolder>().expect("Expected recorder in TypeMap.").clone();
+            let capture = data_read.get::<CaptureHolder>().expect("Expected capture in TypeMap.").clone();
+            let access = data_read.get::<AccessHolder>().expect("Expected access list in TypeMap.").clone();
+            let speakers = data_read.get::<SpeakersHolder>().expect("Expected speakers in TypeMap.").clone();
+            let puppets = data_read.get::<DiscordPuppetsHolder>().expect("Expected discord puppets in TypeMap.").clone();
+            (recorder, capture, access, speakers, puppets)
+        };
+        let receiver = Receiver::new(channel, recorder, capture, access, speakers, puppets, get_store(ctx).await, ctx.http.clone(), guild_id);
         let mut handler = handler_lock.lock().await;
         let discord_input = Input::float_pcm(true, songbird::input::Reader::Extension(Box::new(ts_buffer.clone())));
         handler.play_only_source(discord_input);
         handler.add_global_event(
             CoreEvent::SpeakingStateUpdate.into(),
-            Receiver::new(channel.clone()),
+            receiver.clone(),
         );
 
         handler.add_global_event(
             CoreEvent::SpeakingUpdate.into(),
-            Receiver::new(channel.clone()),
+            receiver.clone(),
         );
 
         handler.add_global_event(
             CoreEvent::VoicePacket.into(),
-            Receiver::new(channel.clone()),
+            receiver.clone(),
         );
 
         handler.add_global_event(
             CoreEvent::RtcpPacket.into(),
-            Receiver::new(channel.clone()),
+            receiver.clone(),
         );
 
         handler.add_global_event(
             CoreEvent::ClientDisconnect.into(),
-            Receiver::new(channel),
+            receiver,
         );
+    Ok(())
+}
 
-    //     check_msg(msg.channel_id.say(&ctx.http, &format!("Joined {}", connect_to.mention())).await);
-    // } else {
-    //     check_msg(msg.channel_id.say(&ctx.http, "Error joining the channel").await);
-    // }
-    println!("joined");
-    interaction.edit_original_interaction_response(&ctx.http, |response| {
-        // response.kind(InteractionResponseType::ChannelMessageWithSource).content("Joined")
-        response.content("Joined")  
+fn register_record(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command.name("record").description("Record the bridged voice chat")
+        .create_option(|option|
+            option.name("start").description("Start recording all speakers")
+            .kind(CommandOptionType::SubCommand))
+        .create_option(|option|
+            option.name("stop").description("Stop the current recording")
+            .kind(CommandOptionType::SubCommand))
+}
+
+async fn handle_record(ctx: &Context, interaction: &ApplicationCommandInteraction) -> anyhow::Result<()> {
+    let start = match interaction.data.options.first().map(|o| o.name.as_str()) {
+        Some("start") => true,
+        Some("stop") => false,
+        _ => bail!("Expected start or stop!"),
+    };
+    let (recorder, ts_commands) = {
+        let data_read = ctx.data.read().await;
+        let recorder = data_read.get::<RecorderHolder>().expect("Expected recorder in TypeMap.").clone();
+        let ts_commands = data_read.get::<TsCommandHolder>().expect("Expected ts commands in TypeMap.").clone();
+        (recorder, ts_commands)
+    };
+
+    let message = if start {
+        let path = recorder.lock().expect("Can't lock recorder!").start()?;
+        println!("Recording to {}", path.display());
+        ctx.set_presence(Some(Activity::playing("🔴 Recording")), OnlineStatus::Online).await;
+        "🔴 This voice chat is now being recorded.".to_string()
+    } else {
+        let session = match recorder.lock().expect("Can't lock recorder!").stop() {
+            Some(session) => session,
+            None => bail!("Not recording!"),
+        };
+        let path = tokio::task::spawn_blocking(move || session.finish()).await?;
+        println!("Recorded to {}", path.display());
+        ctx.set_presence(None, OnlineStatus::Online).await;
+        "⏹️ Recording stopped.".to_string()
+    };
+    let _ = ts_commands.send(TsCommand::Recording(start));
+
+    interaction.create_interaction_response(&ctx.http, |response| {
+        response.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|data| data.content(message))
     })
     .await?;
-    // interaction.create_followup_message(&ctx.http, |response| {
-    //     response.content("Joined")
-    // }).await?;
     Ok(())
 }
 
-#[command]
-#[only_in(guilds)]
-async fn leave(ctx: &Context, msg: &Message) -> CommandResult {
-    let guild = msg.guild(&ctx.cache).expect("No guild found!");
-    let guild_id = guild.id;
-
-    let manager = songbird::get(ctx).await
-        .expect("Songbird Voice client placed in at initialisation.").clone();
-    let has_handler = manager.get(guild_id).is_some();
+fn register_clip(command: &mut CreateApplicationCommand, max_seconds: usize) -> &mut CreateApplicationCommand {
+    command.name("clip").description("Save what was just said")
+        .create_option(|option|
+            option.name("seconds").description("how many seconds to save, defaults to everything buffered")
+            .kind(CommandOptionType::Integer).min_int_value(1).max_int_value(max_seconds.max(1)).required(false))
+        .create_option(|option|
+            option.name("upload").description("upload the clip into this channel, default true")
+            .kind(CommandOptionType::Boolean).required(false))
+}
 
-    if has_handler {
-        if let Err(e) = manager.remove(guild_id).await {
-            check_msg(msg.channel_id.say(&ctx.http, format!("Failed: {:?}", e)).await);
+async fn handle_clip(ctx: &Context, interaction: &ApplicationCommandInteraction) -> anyhow::Result<()> {
+    let mut seconds = None;
+    let mut upload = true;
+    for option in &interaction.data.options {
+        match (option.name.as_str(), &option.resolved) {
+            ("seconds", Some(CommandDataOptionValue::Integer(v))) => match usize::try_from(*v) {
+                Ok(v) if v > 0 => seconds = Some(v),
+                _ => bail!("Expected a positive amount of seconds!"),
+            },
+            ("upload", Some(CommandDataOptionValue::Boolean(v))) => upload = *v,
+            _ => bail!("Unexpected argument {}!", option.name),
         }
+    }
+    let replay = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<ReplayHolder>().expect("Expected replay buffer in TypeMap.").clone()
+    };
+    let seconds = seconds.unwrap_or_else(|| replay.lock().expect("Can't lock replay buffer!").seconds());
 
-        check_msg(msg.channel_id.say(&ctx.http, "Left voice channel").await);
+    interaction.create_interaction_response(&ctx.http, |response| {
+        response.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+    })
+    .await?;
+
+    let (path, seconds) = tokio::task::spawn_blocking(move || crate::replay::save_clip(&replay, seconds)).await??;
+    println!("Saved clip {}", path.display());
+    if upload {
+        interaction.create_followup_message(&ctx.http, |message| {
+            message.content(format!("Last {}s", seconds)).add_file(&path)
+        })
+        .await?;
     } else {
-        check_msg(msg.reply(ctx, "Not in a voice channel").await);
+        interaction.edit_original_interaction_response(&ctx.http, |response| {
+            response.content(format!("Saved last {}s", seconds))
+        })
+        .await?;
     }
+    Ok(())
+}
+
+fn register_play(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command.name("play").description("Play a soundboard clip on both sides of the bridge, or a url in discord")
+        .create_option(|option|
+            option.name("file").description("soundboard clip to play")
+            .kind(CommandOptionType::String).set_autocomplete(true).required(false))
+        .create_option(|option|
+            option.name("url").description("video or audio url to play in discord")
+            .kind(CommandOptionType::String).required(false))
+        .create_option(|option|
+            option.name("volume").description("volume in percent, default 100")
+            .kind(CommandOptionType::Integer).min_int_value(0).max_int_value(200).required(false))
+}
 
+async fn handle_autocomplete(ctx: &Context, autocomplete: &AutocompleteInteraction) -> anyhow::Result<()> {
+    let focused = match autocomplete.data.options.iter().find(|o| o.focused) {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    let input = focused.value.as_ref().and_then(|v| v.as_str()).unwrap_or_default().to_lowercase();
+    // name and value of each choice
+    let choices: Vec<(String, String)> = match (autocomplete.data.name.as_str(), focused.name.as_str()) {
+        ("play", "file") => {
+            let soundboard = get_soundboard(ctx).await;
+            let files = soundboard.lock().expect("Can't lock soundboard!").list()?;
+            files.into_iter().map(|f| (f.clone(), f)).collect()
+        },
+        ("ts_channel", "channel") => {
+            let roster = get_roster(ctx).await;
+            let roster = roster.lock().expect("Can't lock roster!");
+            roster.ts_channels().iter()
+                .map(|c| (if c.has_password { format!("{} (password)", c.path) } else { c.path.clone() }, c.id.to_string()))
+                .collect()
+        },
+        _ => return Ok(()),
+    };
+    autocomplete.create_autocomplete_response(&ctx.http, |response| {
+        // discord allows up to 25 choices
+        for (name, value) in choices.iter().filter(|(name, _)| name.to_lowercase().contains(&input)).take(25) {
+            response.add_string_choice(name, value);
+        }
+        response
+    })
+    .await?;
     Ok(())
 }
 
-#[command]
-#[only_in(guilds)]
-async fn mute(ctx: &Context, msg: &Message) -> CommandResult {
-    let guild = msg.guild(&ctx.cache).expect("No guild found!");
-    let guild_id = guild.id;
+async fn get_roster(ctx: &Context) -> RosterHandle {
+    let data_read = ctx.data.read().await;
+    data_read.get::<RosterHolder>().expect("Expected roster in TypeMap.").clone()
+}
 
-    let manager = songbird::get(ctx).await
-        .expect("Songbird Voice client placed in at initialisation.").clone();
+async fn get_store(ctx: &Context) -> StateHandle {
+    let data_read = ctx.data.read().await;
+    data_read.get::<StateHolder>().expect("Expected state in TypeMap.").clone()
+}
 
-    let handler_lock = match manager.get(guild_id) {
-        Some(handler) => handler,
-        None => {
-            check_msg(msg.reply(ctx, "Not in a voice channel").await);
+async fn get_soundboard(ctx: &Context) -> crate::SoundboardHandle {
+    let data_read = ctx.data.read().await;
+    data_read.get::<SoundboardHolder>().expect("Expected soundboard in TypeMap.").clone()
+}
 
+async fn handle_play(ctx: &Context, interaction: &ApplicationCommandInteraction) -> anyhow::Result<()> {
+    let mut file = None;
+    let mut url = None;
+    let mut volume = 100;
+    for option in &interaction.data.options {
+        match (option.name.as_str(), &option.resolved) {
+            ("file", Some(CommandDataOptionValue::String(v))) => file = Some(v.clone()),
+            ("url", Some(CommandDataOptionValue::String(v))) => url = Some(v.clone()),
+            ("volume", Some(CommandDataOptionValue::Integer(v))) => volume = *v,
+            _ => bail!("Unexpected argument {}!", option.name),
+        }
+    }
+    let file = match (file, url) {
+        (Some(v), None) => v,
+        (None, Some(url)) => {
+            let guild_id = interaction_guild(interaction)?;
+            interaction.create_interaction_response(&ctx.http, |response| {
+                response.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+            })
+            .await?;
+            let message = play_url(ctx, guild_id, &url, volume as f32 / 100.0).await?;
+            interaction.edit_original_interaction_response(&ctx.http, |response| {
+                response.content(message)
+            })
+            .await?;
             return Ok(());
         },
+        _ => bail!("Expected either a file or an url!"),
     };
+    let soundboard = get_soundboard(ctx).await;
+    let path = soundboard.lock().expect("Can't lock soundboard!").clip_path(&file)?;
 
-    let mut handler = handler_lock.lock().await;
-
-    if handler.is_mute() {
-        check_msg(msg.channel_id.say(&ctx.http, "Already muted").await);
+    interaction.create_interaction_response(&ctx.http, |response| {
+        response.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+    })
+    .await?;
+    let clip = tokio::task::spawn_blocking(move || crate::soundboard::load_clip(&path, volume as f32 / 100.0)).await??;
+    let duration = clip.duration();
+    let position = soundboard.lock().expect("Can't lock soundboard!").enqueue(clip);
+    let message = if position == 0 {
+        format!("Playing {} ({:.1}s)", file, duration)
     } else {
-        if let Err(e) = handler.mute(true).await {
-            check_msg(msg.channel_id.say(&ctx.http, format!("Failed: {:?}", e)).await);
-        }
+        format!("Queued {} ({:.1}s) at position {}", file, duration, position)
+    };
+    interaction.edit_original_interaction_response(&ctx.http, |response| {
+        response.content(message)
+    })
+    .await?;
+    Ok(())
+}
 
-        check_msg(msg.channel_id.say(&ctx.http, "Now muted").await);
-    }
+async fn handle_stop(ctx: &Context, interaction: &ApplicationCommandInteraction) -> anyhow::Result<()> {
+    get_soundboard(ctx).await.lock().expect("Can't lock soundboard!").stop();
+    interaction.create_interaction_response(&ctx.http, |response| {
+        response.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|data| data.content("Stopped soundboard"))
+    })
+    .await?;
+    Ok(())
+}
+
+async fn handle_skip(ctx: &Context, interaction: &ApplicationCommandInteraction) -> anyhow::Result<()> {
+    let message = match get_soundboard(ctx).await.lock().expect("Can't lock soundboard!").skip() {
+        Some(name) => format!("Skipped {}", name),
+        None => "Nothing playing".to_string(),
+    };
+    interaction.create_interaction_response(&ctx.http, |response| {
+        response.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|data| data.content(message))
+    })
+    .await?;
+    Ok(())
+}
 
+async fn handle_queue(ctx: &Context, interaction: &ApplicationCommandInteraction) -> anyhow::Result<()> {
+    let message = {
+        let soundboard = get_soundboard(ctx).await;
+        let lock = soundboard.lock().expect("Can't lock soundboard!");
+        let (current, queue) = lock.queue();
+        let mut message = match current {
+            Some(clip) => format!("Playing {} ({:.1}s)", clip.name, clip.duration()),
+            None => "Nothing playing".to_string(),
+        };
+        for (i, clip) in queue.enumerate() {
+            message.push_str(&format!("\n{}. {} ({:.1}s, {}%)", i + 1, clip.name, clip.duration(), (clip.volume * 100.0) as u32));
+        }
+        message
+    };
+    interaction.create_interaction_response(&ctx.http, |response| {
+        response.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|data| data.content(message).ephemeral(true))
+    })
+    .await?;
     Ok(())
 }
 
-#[command]
-async fn ping(context: &Context, msg: &Message) -> CommandResult {
-    check_msg(msg.channel_id.say(&context.http, "Pong!").await);
+fn register_access(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command.name("access").description("Control whose audio crosses the bridge");
+    for (name, description) in [
+        ("allow", "Only bridge allowed speakers of this platform"),
+        ("deny", "Never bridge this speaker"),
+        ("remove", "Remove allow and deny entries"),
+    ] {
+        command.create_option(|option| {
+            option.name(name).description(description).kind(CommandOptionType::SubCommand)
+                .create_sub_option(|o| o.name("user").description("discord user").kind(CommandOptionType::User))
+                .create_sub_option(|o| o.name("role").description("discord role").kind(CommandOptionType::Role))
+                .create_sub_option(|o| o.name("ts_client").description("teamspeak client database ID")
+                    .kind(CommandOptionType::Integer).min_int_value(1))
+                .create_sub_option(|o| o.name("ts_group").description("teamspeak server group ID")
+                    .kind(CommandOptionType::Integer).min_int_value(1))
+        });
+    }
+    command
+        .create_option(|option|
+            option.name("bots").description("Bridge discord bot accounts").kind(CommandOptionType::SubCommand)
+            .create_sub_option(|o| o.name("enabled").description("bridge bots").kind(CommandOptionType::Boolean).required(true)))
+        .create_option(|option|
+            option.name("list").description("Show the current rules").kind(CommandOptionType::SubCommand))
+}
 
+async fn handle_access(ctx: &Context, interaction: &ApplicationCommandInteraction) -> anyhow::Result<()> {
+    let subcommand = match interaction.data.options.first() {
+        Some(v) => v,
+        None => bail!("Expected subcommand!"),
+    };
+    let mut entries = Vec::new();
+    let mut bots = None;
+    for option in &subcommand.options {
+        match (option.name.as_str(), &option.resolved) {
+            ("user", Some(CommandDataOptionValue::User(user, _))) => entries.push(AccessEntry::DiscordUser(user.id.0)),
+            ("role", Some(CommandDataOptionValue::Role(role))) => entries.push(AccessEntry::DiscordRole(role.id.0)),
+            ("ts_client", Some(CommandDataOptionValue::Integer(v))) => entries.push(AccessEntry::TsClient(*v as u64)),
+            ("ts_group", Some(CommandDataOptionValue::Integer(v))) => entries.push(AccessEntry::TsServerGroup(*v as u64)),
+            ("enabled", Some(CommandDataOptionValue::Boolean(v))) => bots = Some(*v),
+            _ => bail!("Unexpected argument {}!", option.name),
+        }
+    }
+    let access = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<AccessHolder>().expect("Expected access list in TypeMap.").clone()
+    };
+
+    let message = {
+        let mut access = access.lock().expect("Can't lock access list!");
+        match subcommand.name.as_str() {
+            "allow" | "deny" | "remove" if entries.is_empty() => bail!("Expected a user, role, ts_client or ts_group!"),
+            "allow" => {
+                entries.iter().for_each(|e| access.allow(*e));
+                format!("Allowed {}", format_entries(entries.into_iter(), ""))
+            },
+            "deny" => {
+                entries.iter().for_each(|e| access.deny(*e));
+                format!("Denied {}", format_entries(entries.into_iter(), ""))
+            },
+            "remove" => {
+                entries.retain(|e| access.remove(*e));
+                if entries.is_empty() {
+                    "No matching rules".to_string()
+                } else {
+                    format!("Removed {}", format_entries(entries.into_iter(), ""))
+                }
+            },
+            "bots" => {
+                let bots = bots.unwrap_or_default();
+                access.set_bridge_bots(bots);
+                if bots { "Bridging bots" } else { "Not bridging bots" }.to_string()
+            },
+            "list" => format!("Allowed: {}\nDenied: {}\nBridging bots: {}",
+                format_entries(access.allowed(), "everyone"), format_entries(access.denied(), "no one"), access.bridge_bots()),
+            _ => bail!("Unknown subcommand {}!", subcommand.name),
+        }
+    };
+    if subcommand.name != "list" {
+        let config = access.lock().expect("Can't lock access list!").config().clone();
+        get_store(ctx).await.lock().expect("Can't lock state!").update(|s| s.access = Some(config))?;
+    }
+    interaction.create_interaction_response(&ctx.http, |response| {
+        response.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|data| data.content(message).ephemeral(true))
+    })
+    .await?;
     Ok(())
 }
 
-#[command]
-#[only_in(guilds)]
-async fn play(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
-    let url = match args.single::<String>() {
-        Ok(url) => url,
-        Err(_) => {
-            check_msg(msg.channel_id.say(&ctx.http, "Must provide a URL to a video or audio").await);
+fn format_entries(entries: impl Iterator<Item = AccessEntry>, empty: &str) -> String {
+    let list: Vec<String> = entries.map(|e| e.to_string()).collect();
+    if list.is_empty() {
+        empty.to_string()
+    } else {
+        list.join(", ")
+    }
+}
 
-            return Ok(());
+fn register_speaker(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command.name("speaker").description("Volume and mute of single speakers, kept across restarts");
+    for (name, description) in [
+        ("volume", "Change the volume of a speaker"),
+        ("mute", "Stop bridging a speaker"),
+        ("unmute", "Bridge a muted speaker again"),
+    ] {
+        command.create_option(|option| {
+            option.name(name).description(description).kind(CommandOptionType::SubCommand);
+            if name == "volume" {
+                option.create_sub_option(|o| o.name("percent").description("volume in percent")
+                    .kind(CommandOptionType::Integer).min_int_value(0).max_int_value(MAX_USER_VOLUME).required(true));
+            }
+            option
+                .create_sub_option(|o| o.name("user").description("discord user").kind(CommandOptionType::User))
+                .create_sub_option(|o| o.name("ts_client").description("teamspeak nickname").kind(CommandOptionType::String))
+        });
+    }
+    command
+}
+
+async fn handle_speaker(ctx: &Context, interaction: &ApplicationCommandInteraction) -> anyhow::Result<()> {
+    let subcommand = match interaction.data.options.first() {
+        Some(v) => v,
+        None => bail!("Expected subcommand!"),
+    };
+    let mut percent = None;
+    let mut user = None;
+    let mut client = None;
+    for option in &subcommand.options {
+        match (option.name.as_str(), &option.resolved) {
+            ("percent", Some(CommandDataOptionValue::Integer(v))) => percent = Some(*v as u32),
+            ("user", Some(CommandDataOptionValue::User(v, _))) => user = Some(v.id),
+            ("ts_client", Some(CommandDataOptionValue::String(v))) => client = Some(v.clone()),
+            _ => bail!("Unexpected argument {}!", option.name),
+        }
+    }
+    let change = |settings: &mut UserSettings| match subcommand.name.as_str() {
+        "volume" => settings.volume = percent.unwrap_or(100),
+        "mute" => settings.muted = true,
+        _ => settings.muted = false,
+    };
+    let description = match subcommand.name.as_str() {
+        "volume" => format!("volume {}%", percent.unwrap_or(100)),
+        "mute" => "muted".to_string(),
+        "unmute" => "unmuted".to_string(),
+        _ => bail!("Unknown subcommand {}!", subcommand.name),
+    };
+    let store = get_store(ctx).await;
+    let message = match (user, client) {
+        (Some(user), None) => {
+            store.lock().expect("Can't lock state!").update(|s| s.update_discord_user(user.0, change))?;
+            apply_discord_settings(ctx).await;
+            format!("<@{}> {}", user, description)
+        },
+        (None, Some(name)) => {
+            let member = get_roster(ctx).await.lock().expect("Can't lock roster!").teamspeak().iter()
+                .find(|m| m.name.eq_ignore_ascii_case(&name))
+                .cloned();
+            let member = match member {
+                Some(v) if !v.uid.is_empty() => v,
+                _ => bail!("No teamspeak client named {} in the channel", name),
+            };
+            store.lock().expect("Can't lock state!").update(|s| s.update_ts_user(&member.uid, change))?;
+            format!("{} {}", member.name, description)
         },
+        _ => bail!("Expected either a user or a ts_client!"),
     };
+    interaction.create_interaction_response(&ctx.http, |response| {
+        response.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|data| data.content(message).ephemeral(true))
+    })
+    .await?;
+    Ok(())
+}
 
-    if !url.starts_with("http") {
-        check_msg(msg.channel_id.say(&ctx.http, "Must provide a valid URL").await);
-
-        return Ok(());
+/// Apply the saved volumes and mutes to all known discord speakers
+async fn apply_discord_settings(ctx: &Context) {
+    let (speakers, sink, store) = {
+        let data_read = ctx.data.read().await;
+        let speakers = data_read.get::<SpeakersHolder>().expect("Expected speakers in TypeMap.").clone();
+        let (_, sink) = data_read.get::<ListenerHolder>().expect("Expected voice pipelines in TypeMap.").clone();
+        let store = data_read.get::<StateHolder>().expect("Expected state in TypeMap.").clone();
+        (speakers, sink, store)
+    };
+    // the receiver locks speakers before the state
+    let users: Vec<(u32, u64)> = speakers.lock().expect("Can't lock speakers!").iter()
+        .map(|(ssrc, speaker)| (*ssrc, speaker.user))
+        .collect();
+    let volumes: Vec<(u32, f32)> = {
+        let store = store.lock().expect("Can't lock state!");
+        users.into_iter().map(|(ssrc, user)| (ssrc, store.state().discord_user(user).gain())).collect()
+    };
+    let mut sink = sink.lock().await;
+    for (ssrc, volume) in volumes {
+        sink.set_volume(ssrc, volume);
     }
+}
 
-    let guild = msg.guild(&ctx.cache).expect("No guild found!");
-    let guild_id = guild.id;
+fn register_settings(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command.name("settings").description("Back up and restore settings changed by commands")
+        .create_option(|option|
+            option.name("export").description("Upload all settings as a file").kind(CommandOptionType::SubCommand))
+        .create_option(|option|
+            option.name("import").description("Replace all settings with an exported file").kind(CommandOptionType::SubCommand)
+            .create_sub_option(|o| o.name("file").description("exported settings").kind(CommandOptionType::Attachment).required(true)))
+}
 
-    let manager = songbird::get(ctx).await
-        .expect("Songbird Voice client placed in at initialisation.").clone();
+async fn handle_settings(ctx: &Context, interaction: &ApplicationCommandInteraction) -> anyhow::Result<()> {
+    let subcommand = match interaction.data.options.first() {
+        Some(v) => v,
+        None => bail!("Expected subcommand!"),
+    };
+    let store = get_store(ctx).await;
+    match subcommand.name.as_str() {
+        "export" => {
+            let exported = store.lock().expect("Can't lock state!").export()?;
+            interaction.create_interaction_response(&ctx.http, |response| {
+                response.kind(InteractionResponseType::ChannelMessageWithSource)
+                    .interaction_response_data(|data| data.add_file((exported.as_bytes(), "bridge_settings.toml")).ephemeral(true))
+            })
+            .await?;
+            Ok(())
+        },
+        "import" => {
+            let attachment = match subcommand.options.first().and_then(|o| o.resolved.as_ref()) {
+                Some(CommandDataOptionValue::Attachment(v)) => v,
+                _ => bail!("Expected a file!"),
+            };
+            let content = String::from_utf8(attachment.download().await?)?;
+            let state = {
+                let mut store = store.lock().expect("Can't lock state!");
+                store.import(&content)?;
+                store.state().clone()
+            };
+            let (access, follow) = {
+                let data_read = ctx.data.read().await;
+                let access = data_read.get::<AccessHolder>().expect("Expected access list in TypeMap.").clone();
+                let follow = data_read.get::<FollowHolder>().expect("Expected follow targets in TypeMap.").clone();
+                (access, follow)
+            };
+            if let Some(config) = state.access.clone() {
+                access.lock().expect("Can't lock access list!").set_config(config);
+            }
+            *follow.lock().expect("Can't lock follow targets!") = crate::follow_targets(&state);
+            apply_discord_settings(ctx).await;
+            respond(ctx, interaction, "Imported settings, the teamspeak channel and the followed teamspeak client apply after a restart".to_string()).await
+        },
+        _ => bail!("Unknown subcommand {}!", subcommand.name),
+    }
+}
 
-    if let Some(handler_lock) = manager.get(guild_id) {
-        let mut handler = handler_lock.lock().await;
+fn register_bridge(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command.name("bridge").description("Bridge state")
+        .create_option(|option|
+            option.name("status").description("Show the health of both sides").kind(CommandOptionType::SubCommand))
+        .create_option(|option|
+            option.name("pause").description("Stop forwarding audio without disconnecting").kind(CommandOptionType::SubCommand)
+            .create_sub_option(register_direction)
+            .create_sub_option(|o| o.name("minutes").description("resume automatically after this many minutes")
+                .kind(CommandOptionType::Integer).min_int_value(1).max_int_value(MAX_PAUSE_MINUTES).required(false)))
+        .create_option(|option|
+            option.name("resume").description("Forward audio again").kind(CommandOptionType::SubCommand)
+            .create_sub_option(register_direction))
+}
 
-        let source = match songbird::ytdl(&url).await {
-            Ok(source) => source,
-            Err(why) => {
-                println!("Err starting source: {:?}", why);
+fn register_direction(option: &mut CreateApplicationCommandOption) -> &mut CreateApplicationCommandOption {
+    option.name("direction").description("which direction").kind(CommandOptionType::String).required(true)
+        .add_string_choice("Teamspeak → Discord", "ts_to_discord")
+        .add_string_choice("Discord → Teamspeak", "discord_to_ts")
+        .add_string_choice("Both", "both")
+}
 
-                check_msg(msg.channel_id.say(&ctx.http, "Error sourcing ffmpeg").await);
+async fn handle_bridge(ctx: &Context, interaction: &ApplicationCommandInteraction) -> anyhow::Result<()> {
+    let subcommand = match interaction.data.options.first() {
+        Some(v) => v,
+        None => bail!("Expected subcommand!"),
+    };
+    match subcommand.name.as_str() {
+        "status" => handle_bridge_status(ctx, interaction).await,
+        "pause" | "resume" => handle_bridge_pause(ctx, interaction, subcommand.name == "pause").await,
+        _ => bail!("Unknown subcommand {}!", subcommand.name),
+    }
+}
 
-                return Ok(());
+async fn handle_bridge_pause(ctx: &Context, interaction: &ApplicationCommandInteraction, pause: bool) -> anyhow::Result<()> {
+    let subcommand = &interaction.data.options[0];
+    let mut direction = None;
+    let mut minutes = None;
+    for option in &subcommand.options {
+        match (option.name.as_str(), &option.resolved) {
+            ("direction", Some(CommandDataOptionValue::String(v))) => direction = Some(v.parse::<Direction>()?),
+            ("minutes", Some(CommandDataOptionValue::Integer(v))) => match u64::try_from(*v) {
+                Ok(v) if (1..=MAX_PAUSE_MINUTES).contains(&v) => minutes = Some(v),
+                _ => bail!("Expected between 1 and {} minutes!", MAX_PAUSE_MINUTES),
             },
-        };
+            _ => bail!("Unexpected argument {}!", option.name),
+        }
+    }
+    let direction = match direction {
+        Some(v) => v,
+        None => bail!("Expected a direction!"),
+    };
+    let status = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<StatusHolder>().expect("Expected status in TypeMap.").clone()
+    };
+    let paused = {
+        let mut status = status.lock().expect("Can't lock status!");
+        status.paused.set(direction, pause, minutes.and_then(|m| m.checked_mul(60)).map(Duration::from_secs));
+        status.paused
+    };
+    respond(ctx, interaction, format!("Paused: {}", format_paused(&paused))).await
+}
 
-        handler.play_source(source);
+async fn handle_bridge_status(ctx: &Context, interaction: &ApplicationCommandInteraction) -> anyhow::Result<()> {
+    let status = {
+        let data_read = ctx.data.read().await;
+        data_read.get::<StatusHolder>().expect("Expected status in TypeMap.").clone()
+    };
+    let discord = match get_call(ctx, interaction_guild(interaction)?).await {
+        Ok(call) => match call.lock().await.current_channel() {
+            Some(channel) => format!("Connected to <#{}>", channel.0),
+            None => "Connecting".to_string(),
+        },
+        Err(_) => "Not in a voice channel".to_string(),
+    };
 
-        check_msg(msg.channel_id.say(&ctx.http, "Playing song").await);
-    } else {
-        check_msg(msg.channel_id.say(&ctx.http, "Not in a voice channel to play in").await);
+    let (teamspeak, uptime, paused, discord_to_ts, ts_to_discord, warnings) = {
+        let status = status.lock().expect("Can't lock status!");
+        let teamspeak = match &status.teamspeak {
+            Some(ts) => format!("Connected to {}\nChannel {}", ts.server, ts.channel),
+            None => "Disconnected".to_string(),
+        };
+        let warnings: Vec<String> = status.warnings()
+            .map(|(time, message)| format!("{} ago: {}", format_duration(time.elapsed()), message))
+            .collect();
+        (teamspeak, format_duration(status.started.elapsed()), format_paused(&status.paused), status.discord_to_ts, status.ts_to_discord, warnings)
+    };
+    let warnings = if warnings.is_empty() { "None".to_string() } else { warnings.join("\n") };
+
+    interaction.create_interaction_response(&ctx.http, |response| {
+        response.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|data| data.embed(|embed| {
+                embed.title("Bridge status")
+                    .field("Teamspeak", teamspeak, true)
+                    .field("Discord voice", discord, true)
+                    .field("Uptime", uptime, true)
+                    .field("Paused", paused, false)
+                    .field("Discord → Teamspeak", format_direction(&discord_to_ts), true)
+                    .field("Teamspeak → Discord", format_direction(&ts_to_discord), true)
+                    .field("Recent warnings", warnings, false)
+            }))
+    })
+    .await?;
+    Ok(())
+}
+
+/// Paused directions with their remaining time
+fn format_paused(paused: &Paused) -> String {
+    let format = |name: &str, until: Option<Instant>| match until {
+        Some(until) => format!("{} (resumes in {})", name, format_duration(until.saturating_duration_since(Instant::now()))),
+        None => name.to_string(),
+    };
+    let mut directions = Vec::new();
+    if paused.discord_to_ts {
+        directions.push(format("Discord → Teamspeak", paused.discord_to_ts_until));
+    }
+    if paused.ts_to_discord {
+        directions.push(format("Teamspeak → Discord", paused.ts_to_discord_until));
     }
+    if directions.is_empty() { "Nothing".to_string() } else { directions.join(", ") }
+}
 
-    Ok(())
+fn format_direction(stats: &DirectionStats) -> String {
+    let buffered = match stats.buffered_ms {
+        Some(ms) => format!("{:.0}ms", ms),
+        None => "unavailable".to_string(),
+    };
+    format!("Speakers: {}\nJitter buffer: {}\nPacket loss: {:.1}% ({} of {})\nGain reduction: {:.1}dB",
+        stats.active_speakers, buffered, stats.loss_percent(), stats.lost_packets, stats.received_packets + stats.lost_packets,
+        stats.gain_reduction_db)
 }
 
 #[command]
 #[only_in(guilds)]
-async fn undeafen(ctx: &Context, msg: &Message) -> CommandResult {
-    let guild = msg.guild(&ctx.cache).expect("No guild found!");
-    let guild_id = guild.id;
+async fn deafen(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild_id = msg.guild_id.expect("No guild found!");
+    say_result(ctx, msg, set_deafen(ctx, guild_id, true).await).await;
+    Ok(())
+}
 
-    let manager = songbird::get(ctx).await
-        .expect("Songbird Voice client placed in at initialisation.").clone();
+#[command]
+#[only_in(guilds)]
+async fn leave(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild_id = msg.guild_id.expect("No guild found!");
+    say_result(ctx, msg, leave_voice(ctx, guild_id).await).await;
+    Ok(())
+}
 
-    if let Some(handler_lock) = manager.get(guild_id) {
-        let mut handler = handler_lock.lock().await;
-        if let Err(e) = handler.deafen(false).await {
-            check_msg(msg.channel_id.say(&ctx.http, format!("Failed: {:?}", e)).await);
-        }
+#[command]
+#[only_in(guilds)]
+async fn mute(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild_id = msg.guild_id.expect("No guild found!");
+    say_result(ctx, msg, set_mute(ctx, guild_id, true).await).await;
+    Ok(())
+}
 
-        check_msg(msg.channel_id.say(&ctx.http, "Undeafened").await);
-    } else {
-        check_msg(msg.channel_id.say(&ctx.http, "Not in a voice channel to undeafen in").await);
-    }
+#[command]
+async fn ping(context: &Context, msg: &Message) -> CommandResult {
+    check_msg(msg.channel_id.say(&context.http, "Pong!").await);
 
     Ok(())
 }
 
 #[command]
 #[only_in(guilds)]
-async fn unmute(ctx: &Context, msg: &Message) -> CommandResult {
-    let guild = msg.guild(&ctx.cache).expect("No guild found!");
-    let guild_id = guild.id;
-    
-    let manager = songbird::get(ctx).await
-        .expect("Songbird Voice client placed in at initialisation.").clone();
+async fn play(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+    let url = match args.single::<String>() {
+        Ok(url) => url,
+        Err(_) => {
+            check_msg(msg.channel_id.say(&ctx.http, "Must provide a URL to a video or audio").await);
 
-    if let Some(handler_lock) = manager.get(guild_id) {
-        let mut handler = handler_lock.lock().await;
-        if let Err(e) = handler.mute(false).await {
-            check_msg(msg.channel_id.say(&ctx.http, format!("Failed: {:?}", e)).await);
-        }
+            return Ok(());
+        },
+    };
+    let guild_id = msg.guild_id.expect("No guild found!");
+    say_result(ctx, msg, play_url(ctx, guild_id, &url, 1.0).await).await;
+    Ok(())
+}
 
-        check_msg(msg.channel_id.say(&ctx.http, "Unmuted").await);
-    } else {
-        check_msg(msg.channel_id.say(&ctx.http, "Not in a voice channel to unmute in").await);
-    }
+#[command]
+#[only_in(guilds)]
+async fn undeafen(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild_id = msg.guild_id.expect("No guild found!");
+    say_result(ctx, msg, set_deafen(ctx, guild_id, false).await).await;
+    Ok(())
+}
 
+#[command]
+#[only_in(guilds)]
+async fn unmute(ctx: &Context, msg: &Message) -> CommandResult {
+    let guild_id = msg.guild_id.expect("No guild found!");
+    say_result(ctx, msg, set_mute(ctx, guild_id, false).await).await;
     Ok(())
 }
 
+/// Reply with the result of a prefix command
+async fn say_result(ctx: &Context, msg: &Message, result: anyhow::Result<String>) {
+    let message = match result {
+        Ok(v) => v,
+        Err(e) => format!("Failed: {}", e),
+    };
+    check_msg(msg.channel_id.say(&ctx.http, message).await);
+}
+
 /// Checks that a message successfully sent; if not, then logs why to stdout.
 fn check_msg(result: SerenityResult<Message>) {
     if let Err(why) = result {
@@ -392,16 +1617,87 @@ fn check_msg(result: SerenityResult<Message>) {
     }
 }
 
+/// Known speakers by SSRC, shared by all event handlers
+pub(crate) type SpeakerHandle = Arc<std::sync::Mutex<HashMap<u32, DiscordSpeaker>>>;
+
+/// Discord user behind an SSRC, used for the access list and puppets
+pub(crate) struct DiscordSpeaker {
+    pub user: u64,
+    pub roles: Vec<u64>,
+    pub bot: bool,
+    /// Display name in the guild
+    pub name: String,
+}
+
+#[derive(Clone)]
 struct Receiver{
     sink: crate::AudioBufferDiscord,
+    recorder: crate::RecorderHandle,
+    capture: CaptureHandle,
+    access: AccessHandle,
+    speakers: SpeakerHandle,
+    /// Our puppet bots, playing teamspeak speakers
+    puppets: DiscordPuppetsHandle,
+    store: StateHandle,
+    http: Arc<Http>,
+    guild_id: GuildId,
 }
 
 impl Receiver {
-    pub fn new(voice_receiver: crate::AudioBufferDiscord) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(voice_receiver: crate::AudioBufferDiscord, recorder: crate::RecorderHandle, capture: CaptureHandle, access: AccessHandle, speakers: SpeakerHandle, puppets: DiscordPuppetsHandle, store: StateHandle, http: Arc<Http>, guild_id: GuildId) -> Self {
         // You can manage state here, such as a buffer of audio packet bytes so
         // you can later store them in intervals.
         Self {
             sink: voice_receiver,
+            recorder,
+            capture,
+            access,
+            speakers,
+            puppets,
+            store,
+            http,
+            guild_id,
+        }
+    }
+
+    /// Look up the member behind an SSRC, for roles and the bot flag.
+    fn resolve_speaker(&self, ssrc: u32, user: u64) {
+        if self.speakers.lock().expect("Can't lock speakers!").get(&ssrc).map(|s| s.user) == Some(user) {
+            return;
+        }
+        let (http, guild_id, speakers) = (self.http.clone(), self.guild_id, self.speakers.clone());
+        let (sink, store) = (self.sink.clone(), self.store.clone());
+        tokio::spawn(async move {
+            match guild_id.member(&http, user).await {
+                Ok(member) => {
+                    let speaker = DiscordSpeaker {
+                        user,
+                        roles: member.roles.iter().map(|r| r.0).collect(),
+                        bot: member.user.bot,
+                        name: member.display_name().into_owned(),
+                    };
+                    speakers.lock().expect("Can't lock speakers!").insert(ssrc, speaker);
+                    let volume = store.lock().expect("Can't lock state!").state().discord_user(user).gain();
+                    sink.lock().await.set_volume(ssrc, volume);
+                },
+                Err(e) => eprintln!("Failed to fetch member {}: {}", user, e),
+            }
+        });
+    }
+
+    /// Whether audio of this SSRC may be bridged
+    fn is_allowed(&self, ssrc: u32) -> bool {
+        // they play teamspeak, bridging them back would echo
+        if self.puppets.lock().expect("Can't lock discord puppets!").is_puppet_ssrc(ssrc) {
+            return false;
+        }
+        let access = self.access.lock().expect("Can't lock access list!");
+        match self.speakers.lock().expect("Can't lock speakers!").get(&ssrc) {
+            Some(speaker) => access.discord_allowed(speaker.user, &speaker.roles, speaker.bot)
+                && !self.store.lock().expect("Can't lock state!").state().discord_user(speaker.user).muted,
+            // unknown until the member is fetched
+            None => !access.has_discord_rules(),
         }
     }
 }
@@ -426,6 +1722,13 @@ impl VoiceEventHandler for Receiver {
                 // SSRCs and map the SSRC to the User ID and maintain this state.
                 // Using this map, you can map the `ssrc` in `voice_packet`
                 // to the user ID and handle their audio packets separately.
+                if let Some(user) = user_id {
+                    if self.puppets.lock().expect("Can't lock discord puppets!").check_user(*ssrc, user.0) {
+                        return None;
+                    }
+                    self.recorder.lock().expect("Can't lock recorder!").set_label(Track::Discord(*ssrc), user.0.to_string());
+                    self.resolve_speaker(*ssrc, user.0);
+                }
                 //println!(
                 //     "Speaking state update: user {:?} has SSRC {:?}, using {:?}",
                 //     user_id,
@@ -448,6 +1751,9 @@ impl VoiceEventHandler for Receiver {
 
                 // get raw opus package, we don't decode here and leave that to the AudioHandler
                 let packet = data.packet;
+                if !self.is_allowed(packet.ssrc) {
+                    return None;
+                }
                 let last_bytes = packet.payload.len() - data.payload_end_pad;
                 let data = &packet.payload[data.payload_offset..last_bytes];
                 let start = if packet.extension != 0 {
@@ -462,6 +1768,8 @@ impl VoiceEventHandler for Receiver {
                     0
                 };
                 let opus_slice = &data[start..];
+                self.capture.lock().expect("Can't lock capture!")
+                    .push(Source::Discord, packet.ssrc, packet.sequence.0.0, packet.timestamp.0.0, opus_slice);
                 let dur;
                 {
                     let time = std::time::Instant::now();
@@ -490,6 +1798,17 @@ impl VoiceEventHandler for Receiver {
                 // speaking or connecting.
 
                 println!("Client disconnected: user {:?}", user_id);
+                let mut left = Vec::new();
+                self.speakers.lock().expect("Can't lock speakers!").retain(|ssrc, s| {
+                    if s.user == user_id.0 {
+                        left.push(*ssrc);
+                    }
+                    s.user != user_id.0
+                });
+                let mut sink = self.sink.lock().await;
+                for ssrc in left {
+                    sink.forget_volume(ssrc);
+                }
             },
             _ => {
                 // We won't be registering this struct for any more event classes.
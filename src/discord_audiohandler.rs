@@ -21,6 +21,7 @@ use slog::{Logger, debug, info, o, trace, warn};
 use tsclientlib::audio::Error;
 
 use crate::ClientId;
+use crate::status::LossCounter;
 
 const SAMPLE_RATE: SampleRate = SampleRate::Hz48000;
 const CHANNELS: Channels = Channels::Stereo;
@@ -112,6 +113,10 @@ pub struct AudioHandler<Id: Clone + Debug + Eq + Hash + PartialEq = ClientId> {
 	///
 	/// Updated when a new queue gets added.
 	avg_buffer_samples: usize,
+	/// Packet loss of all clients
+	loss: LossCounter<Id>,
+	/// Volume of new queues by client, from the user settings
+	volumes: HashMap<Id, f32>,
 }
 
 impl<T: Copy + Default + Ord> SlidingWindowMinimum<T> {
@@ -256,7 +261,7 @@ impl AudioQueue {
 		self.decoded_buffer.resize(self.decoded_pos + len * CHANNEL_NUM, 0.0);
 		let len = self
 			.decoder
-			.decode_float(packet_data.as_deref(), &mut self.decoded_buffer[self.decoded_pos..], fec)
+			.decode_float(packet_data, &mut self.decoded_buffer[self.decoded_pos..], fec)
 			.map_err(|e| Error::Decode {
 				error: e,
 				packet: packet.map(|p| p.packet.to_owned()),
@@ -291,7 +296,7 @@ impl AudioQueue {
 	///
 	/// Returns `true` in the second return value when the stream ended,
 	/// `false` when it continues normally.
-	pub fn get_next_data(&mut self, len: usize) -> Result<(&[f32], bool)> {
+	pub fn get_next_data(&mut self, len: usize) -> Result<(&mut [f32], bool)> {
 		if self.buffering_samples > 0 {
 			if self.buffered_for_samples >= MAX_BUFFER_TIME {
 				self.buffering_samples = 0;
@@ -304,7 +309,7 @@ impl AudioQueue {
 				trace!(self.logger, "Buffering";
 					"buffered_for_samples" => self.buffered_for_samples,
 					"buffering_samples" => self.buffering_samples);
-				return Ok((&[], false));
+				return Ok((&mut [], false));
 			}
 		}
 		// Need to refill buffer
@@ -329,7 +334,7 @@ impl AudioQueue {
 			if let Some(packet) = self.packet_buffer.pop_front() {
 				if packet.packet.len() <= 1 {
 					// End of stream
-					return Ok((&self.decoded_buffer, true));
+					return Ok((&mut self.decoded_buffer, true));
 				}
 
 				self.packet_buffer_samples -= packet.samples;
@@ -402,15 +407,44 @@ impl AudioQueue {
 		}
 
 		self.decoded_pos = len;
-		Ok((&self.decoded_buffer[..len], false))
+		Ok((&mut self.decoded_buffer[..len], false))
 	}
 }
 
 impl<Id: Clone + Debug + Eq + Hash + PartialEq> AudioHandler<Id> {
 	pub fn new(logger: Logger) -> Self {
-		Self { logger, queues: Default::default(), avg_buffer_samples: 0 }
+		Self { logger, queues: Default::default(), avg_buffer_samples: 0, loss: Default::default(), volumes: Default::default() }
 	}
 
+	/// Received and lost packets of all clients
+	pub fn packet_stats(&self) -> (u64, u64) { (self.loss.received, self.loss.lost) }
+
+	/// Average amount of buffered samples per queue, interleaved
+	pub fn avg_buffered_samples(&self) -> Option<usize> {
+		if self.queues.is_empty() {
+			return None;
+		}
+		let sum: usize = self.queues.values()
+			.map(|q| q.packet_buffer_samples * CHANNEL_NUM + q.decoded_buffer.len().saturating_sub(q.decoded_pos))
+			.sum();
+		Some(sum / self.queues.len())
+	}
+
+	/// Set the volume of a client, for its current and future queues
+	pub fn set_volume(&mut self, id: Id, volume: f32) {
+		if let Some(queue) = self.queues.get_mut(&id) {
+			queue.volume = volume;
+		}
+		if volume == 1.0 {
+			self.volumes.remove(&id);
+		} else {
+			self.volumes.insert(id, volume);
+		}
+	}
+
+	/// Drop the volume of a client which left
+	pub fn forget_volume(&mut self, id: Id) { self.volumes.remove(&id); }
+
 	/// Delete all queues
 	pub fn reset(&mut self) { self.queues.clear(); }
 
@@ -418,16 +452,17 @@ impl<Id: Clone + Debug + Eq + Hash + PartialEq> AudioHandler<Id> {
 	///
 	/// Returns the clients that are not talking anymore.
 	pub fn fill_buffer(&mut self, buf: &mut [f32]) -> Vec<Id> {
-		self.fill_buffer_with_proc(buf, |_, _| {})
+		self.fill_buffer_with_proc(buf, |_, _, _| {})
 	}
 
 	/// `buf` is not cleared before filling it.
 	///
 	/// Same as [`fill_buffer`] but before merging a queue into the output buffer, a preprocessor
-	/// function is called. The queue volume is applied after calling the preprocessor.
+	/// function is called, which may modify the samples. The queue volume is applied after
+	/// calling the preprocessor, it gets passed to the preprocessor for audio played elsewhere.
 	///
 	/// Returns the clients that are not talking anymore.
-	pub fn fill_buffer_with_proc<F: FnMut(&Id, &[f32])>(
+	pub fn fill_buffer_with_proc<F: FnMut(&Id, f32, &mut [f32])>(
 		&mut self, buf: &mut [f32], mut handle: F,
 	) -> Vec<Id> {
 		trace!(self.logger, "Filling audio buffer"; "len" => buf.len());
@@ -445,7 +480,7 @@ impl<Id: Clone + Debug + Eq + Hash + PartialEq> AudioHandler<Id> {
 					warn!(self.logger, "Failed to decode audio packet"; "error" => %e);
 				}
 				Ok((r, is_end)) => {
-					handle(id, &r);
+					handle(id, vol, r);
 					for i in 0..r.len() {
 						buf[i] += r[i] * vol;
 					}
@@ -457,7 +492,7 @@ impl<Id: Clone + Debug + Eq + Hash + PartialEq> AudioHandler<Id> {
 		}
 
 		for id in &to_remove {
-			self.queues.remove(&id);
+			self.queues.remove(id);
 		}
 		to_remove
 	}
@@ -468,6 +503,7 @@ impl<Id: Clone + Debug + Eq + Hash + PartialEq> AudioHandler<Id> {
 	pub fn handle_packet(&mut self, id: Id, sequence: u16, packet: Vec<u8>) -> Result<Option<Id>> {
 		if let Some(queue) = self.queues.get_mut(&id) {
 			queue.add_packet(sequence, packet)?;
+			self.loss.record(id, sequence);
 			Ok(None)
 		} else {
 
@@ -484,8 +520,351 @@ impl<Id: Clone + Debug + Eq + Hash + PartialEq> AudioHandler<Id> {
 						.sum::<usize>() / self.queues.len();
 			}
 			queue.buffering_samples = self.avg_buffer_samples;
+			if let Some(volume) = self.volumes.get(&id) {
+				queue.volume = *volume;
+			}
 			self.queues.insert(id.clone(), queue);
+			self.loss.record(id.clone(), sequence);
 			Ok(Some(id))
 		}
 	}
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+	use std::f32::consts::PI;
+
+	use audiopus::coder::Encoder;
+	use audiopus::Application;
+
+	use super::*;
+	use crate::STEREO_20MS;
+
+	const CLIENT: u32 = 1;
+
+	/// Encodes a continuous 440 Hz sine into 20 ms stereo packets.
+	struct Sine {
+		encoder: Encoder,
+		pos: usize,
+	}
+
+	impl Sine {
+		fn new() -> Self {
+			let mut encoder = Encoder::new(SAMPLE_RATE, CHANNELS, Application::Voip).unwrap();
+			encoder.set_inband_fec(true).unwrap();
+			encoder.set_packet_loss_perc(20).unwrap();
+			Self { encoder, pos: 0 }
+		}
+
+		fn packet(&mut self) -> Vec<u8> {
+			let mut frame = [0.0; STEREO_20MS];
+			for sample in frame.chunks_mut(CHANNEL_NUM) {
+				let value = (self.pos as f32 * 440.0 * 2.0 * PI / 48_000.0).sin() * 0.5;
+				sample.iter_mut().for_each(|s| *s = value);
+				self.pos += 1;
+			}
+			let mut packet = vec![0; crate::MAX_OPUS_FRAME_SIZE];
+			let len = self.encoder.encode_float(&frame, &mut packet).unwrap();
+			packet.truncate(len);
+			packet
+		}
+
+		fn packets(&mut self, count: usize) -> Vec<Vec<u8>> { (0..count).map(|_| self.packet()).collect() }
+	}
+
+	fn handler() -> AudioHandler<u32> { AudioHandler::new(Logger::root(slog::Discard, o!())) }
+
+	/// Play one 20 ms frame, returns the output and how many samples the client contributed.
+	fn fill(handler: &mut AudioHandler<u32>) -> (Vec<f32>, usize) {
+		let mut buf = vec![0.0; STEREO_20MS];
+		let mut len = 0;
+		handler.fill_buffer_with_proc(&mut buf, |_, _, r| len = r.len());
+		(buf, len)
+	}
+
+	fn rms(samples: &[f32]) -> f32 { (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt() }
+
+	fn ids(handler: &AudioHandler<u32>) -> Vec<u16> { handler.queues[&CLIENT].packet_buffer.iter().map(|p| p.id).collect() }
+
+	fn queue(handler: &AudioHandler<u32>) -> &AudioQueue { &handler.queues[&CLIENT] }
+
+	#[test]
+	fn plays_packets_in_order() {
+		let mut handler = handler();
+		let mut sine = Sine::new();
+		assert_eq!(handler.handle_packet(CLIENT, 0, sine.packet()).unwrap(), Some(CLIENT));
+		for id in 1..5 {
+			assert_eq!(handler.handle_packet(CLIENT, id, sine.packet()).unwrap(), None);
+		}
+		assert_eq!(queue(&handler).packet_buffer_samples, 5 * USUAL_FRAME_SIZE);
+
+		for i in 0..5 {
+			let (buf, len) = fill(&mut handler);
+			assert_eq!(len, STEREO_20MS);
+			assert!(rms(&buf) > 0.1, "frame {} is silent", i);
+			assert_eq!(queue(&handler).next_id, i + 1);
+			assert_eq!(queue(&handler).packet_loss_num, 0);
+		}
+		assert!(queue(&handler).packet_buffer.is_empty());
+		assert_eq!(queue(&handler).packet_buffer_samples, 0);
+		assert_eq!(handler.packet_stats(), (5, 0));
+	}
+
+	#[test]
+	fn reorders_packets() {
+		let mut handler = handler();
+		let packets = Sine::new().packets(4);
+		for id in [0, 2, 3, 1] {
+			handler.handle_packet(CLIENT, id, packets[usize::from(id)].clone()).unwrap();
+		}
+		assert_eq!(ids(&handler), [0, 1, 2, 3]);
+
+		for i in 0..4 {
+			let (buf, len) = fill(&mut handler);
+			assert_eq!(len, STEREO_20MS);
+			assert!(rms(&buf) > 0.1, "frame {} is silent", i);
+			assert_eq!(queue(&handler).packet_loss_num, 0);
+		}
+		assert_eq!(queue(&handler).next_id, 4);
+		assert_eq!(handler.packet_stats(), (4, 0));
+	}
+
+	#[test]
+	fn rejects_duplicates() {
+		let mut handler = handler();
+		let packets = Sine::new().packets(3);
+		handler.handle_packet(CLIENT, 0, packets[0].clone()).unwrap();
+		handler.handle_packet(CLIENT, 2, packets[2].clone()).unwrap();
+		assert!(matches!(handler.handle_packet(CLIENT, 2, packets[2].clone()), Err(Error::Duplicate(2))));
+		assert!(matches!(handler.handle_packet(CLIENT, 0, packets[0].clone()), Err(Error::Duplicate(0))));
+		// filling the gap still works after a duplicate
+		handler.handle_packet(CLIENT, 1, packets[1].clone()).unwrap();
+		assert_eq!(ids(&handler), [0, 1, 2]);
+		assert_eq!(queue(&handler).packet_buffer_samples, 3 * USUAL_FRAME_SIZE);
+		assert_eq!(handler.packet_stats(), (3, 0));
+	}
+
+	#[test]
+	fn rejects_late_packets() {
+		let mut handler = handler();
+		let packets = Sine::new().packets(3);
+		handler.handle_packet(CLIENT, 0, packets[0].clone()).unwrap();
+		handler.handle_packet(CLIENT, 1, packets[1].clone()).unwrap();
+		fill(&mut handler);
+		fill(&mut handler);
+		assert!(matches!(handler.handle_packet(CLIENT, 0, packets[0].clone()),
+			Err(Error::TooLate { wanted: 2, got: 0 })));
+		// too far in the future is the same as too late
+		assert!(matches!(handler.handle_packet(CLIENT, 2 + MAX_BUFFER_PACKETS as u16 + 1, packets[2].clone()),
+			Err(Error::TooLate { wanted: 2, .. })));
+		handler.handle_packet(CLIENT, 2, packets[2].clone()).unwrap();
+		assert_eq!(ids(&handler), [2]);
+	}
+
+	#[test]
+	fn rejects_packets_when_full() {
+		let mut handler = handler();
+		let mut sine = Sine::new();
+		let packet = sine.packet();
+		for id in 0..MAX_BUFFER_PACKETS as u16 {
+			handler.handle_packet(CLIENT, id, packet.clone()).unwrap();
+		}
+		assert!(matches!(handler.handle_packet(CLIENT, MAX_BUFFER_PACKETS as u16, packet),
+			Err(Error::QueueFull)));
+		assert_eq!(queue(&handler).packet_buffer.len(), MAX_BUFFER_PACKETS);
+	}
+
+	#[test]
+	fn uses_fec_for_single_loss() {
+		let mut handler = handler();
+		let packets = Sine::new().packets(4);
+		for id in [0, 2, 3] {
+			handler.handle_packet(CLIENT, id, packets[usize::from(id)].clone()).unwrap();
+		}
+		fill(&mut handler);
+
+		// packet 1 is recovered from the FEC data in packet 2, which stays queued
+		let (buf, len) = fill(&mut handler);
+		assert_eq!(len, STEREO_20MS);
+		assert!(rms(&buf) > 0.1);
+		assert_eq!(queue(&handler).next_id, 2);
+		assert_eq!(ids(&handler), [2, 3]);
+		assert_eq!(queue(&handler).packet_loss_num, 1);
+
+		let (buf, len) = fill(&mut handler);
+		assert_eq!(len, STEREO_20MS);
+		assert!(rms(&buf) > 0.1);
+		assert_eq!(queue(&handler).next_id, 3);
+		assert_eq!(queue(&handler).packet_loss_num, 0);
+		assert_eq!(handler.packet_stats(), (3, 1));
+	}
+
+	#[test]
+	fn conceals_longer_loss() {
+		let mut handler = handler();
+		let packets = Sine::new().packets(4);
+		handler.handle_packet(CLIENT, 0, packets[0].clone()).unwrap();
+		handler.handle_packet(CLIENT, 3, packets[3].clone()).unwrap();
+		fill(&mut handler);
+
+		// packet 1 without FEC data, packet 2 from the FEC data of packet 3
+		for next_id in [2, 3] {
+			let (_, len) = fill(&mut handler);
+			assert_eq!(len, STEREO_20MS);
+			assert_eq!(queue(&handler).next_id, next_id);
+			assert_eq!(ids(&handler), [3]);
+		}
+		assert_eq!(queue(&handler).packet_loss_num, 2);
+		let (buf, _) = fill(&mut handler);
+		assert!(rms(&buf) > 0.1);
+		assert_eq!(queue(&handler).packet_loss_num, 0);
+		assert!(queue(&handler).packet_buffer.is_empty());
+	}
+
+	#[test]
+	fn removes_talker_after_losses() {
+		let mut handler = handler();
+		handler.handle_packet(CLIENT, 0, Sine::new().packet()).unwrap();
+		fill(&mut handler);
+		for _ in 0..MAX_PACKET_LOSSES {
+			let (_, len) = fill(&mut handler);
+			assert_eq!(len, STEREO_20MS);
+		}
+		assert_eq!(fill(&mut handler).1, 0);
+		assert!(handler.queues.is_empty());
+	}
+
+	#[test]
+	fn removes_talker_at_end_of_stream() {
+		let mut handler = handler();
+		handler.handle_packet(CLIENT, 0, Sine::new().packet()).unwrap();
+		handler.handle_packet(CLIENT, 1, Vec::new()).unwrap();
+		let mut buf = vec![0.0; STEREO_20MS];
+		assert!(handler.fill_buffer(&mut buf).is_empty());
+		assert_eq!(handler.fill_buffer(&mut buf), [CLIENT]);
+		assert!(handler.queues.is_empty());
+	}
+
+	#[test]
+	fn wraps_sequence_numbers() {
+		let mut handler = handler();
+		let packets = Sine::new().packets(6);
+		let start = u16::MAX - 2;
+		// 65533, 65534, 0, 65535, 1, 2
+		for i in [0, 1, 3, 2, 4, 5] {
+			handler.handle_packet(CLIENT, start.wrapping_add(i), packets[usize::from(i)].clone()).unwrap();
+		}
+		assert_eq!(ids(&handler), [65533, 65534, 65535, 0, 1, 2]);
+
+		for i in 0..6u16 {
+			let (buf, len) = fill(&mut handler);
+			assert_eq!(len, STEREO_20MS);
+			assert!(rms(&buf) > 0.1, "frame {} is silent", i);
+			assert_eq!(queue(&handler).next_id, start.wrapping_add(i + 1));
+			assert_eq!(queue(&handler).packet_loss_num, 0);
+		}
+		assert!(matches!(handler.handle_packet(CLIENT, u16::MAX, packets[2].clone()),
+			Err(Error::TooLate { wanted: 3, got: u16::MAX })));
+		assert_eq!(handler.packet_stats(), (6, 0));
+	}
+
+	#[test]
+	fn new_talker_buffers() {
+		let mut handler = handler();
+		let mut first = Sine::new();
+		let mut second = Sine::new();
+		handler.handle_packet(CLIENT, 0, first.packet()).unwrap();
+		handler.handle_packet(2, 0, second.packet()).unwrap();
+		// the first talker had nothing buffered, so wait for one more frame
+		assert_eq!(handler.queues[&2].buffering_samples, USUAL_FRAME_SIZE);
+		fill(&mut handler);
+		assert_eq!(handler.queues[&2].next_id, 0);
+
+		handler.handle_packet(2, 1, second.packet()).unwrap();
+		assert_eq!(handler.queues[&2].buffering_samples, 0);
+		fill(&mut handler);
+		assert_eq!(handler.queues[&2].next_id, 1);
+	}
+
+	#[test]
+	fn truncates_full_buffer() {
+		let mut handler = handler();
+		let mut sine = Sine::new();
+		let buffered = 45;
+		for id in 0..buffered {
+			handler.handle_packet(CLIENT, id, sine.packet()).unwrap();
+		}
+		let mut next = buffered;
+		let mut frames = 0;
+		while queue(&handler).packet_buffer.len() >= buffered as usize - 1 {
+			let (buf, len) = fill(&mut handler);
+			assert_eq!(len, STEREO_20MS);
+			assert!(rms(&buf) > 0.1, "frame {} is silent", frames);
+			handler.handle_packet(CLIENT, next, sine.packet()).unwrap();
+			next += 1;
+			frames += 1;
+			assert!(frames < 1000, "buffer never truncated");
+		}
+		// the initial empty buffer has to leave the window first
+		assert!(frames >= usize::from(LAST_BUFFER_SIZE_COUNT), "truncated after {} frames", frames);
+		let queue = queue(&handler);
+		assert!(queue.packet_buffer.len() <= 2, "{} packets left", queue.packet_buffer.len());
+		assert_eq!(queue.next_id, queue.packet_buffer.front().unwrap().id);
+		assert_eq!(queue.packet_buffer_samples, queue.packet_buffer.len() * USUAL_FRAME_SIZE);
+	}
+
+	#[test]
+	fn speeds_up_stable_buffer() {
+		let mut handler = handler();
+		let mut sine = Sine::new();
+		let buffered = 4;
+		for id in 0..buffered {
+			handler.handle_packet(CLIENT, id, sine.packet()).unwrap();
+		}
+		let mut lengths = Vec::new();
+		for frame in 0..1000 {
+			let (buf, len) = fill(&mut handler);
+			assert_eq!(len, STEREO_20MS);
+			assert!(rms(&buf) > 0.1, "frame {} is silent", frame);
+			lengths.push(queue(&handler).packet_buffer.len());
+			handler.handle_packet(CLIENT, buffered + frame, sine.packet()).unwrap();
+		}
+		// the window still contains the empty buffer of the first packet
+		let window = usize::from(LAST_BUFFER_SIZE_COUNT) - 1;
+		assert!(lengths[..window].iter().all(|l| *l == buffered as usize - 1));
+		// playing faster than packets arrive used up part of the buffer
+		assert!(lengths[window..].iter().all(|l| *l < buffered as usize - 1), "{:?}", &lengths[window..]);
+		assert_eq!(queue(&handler).packet_loss_num, 0);
+	}
+
+	#[test]
+	fn sliding_window_minimum() {
+		let mut window = SlidingWindowMinimum::<u8>::new(3);
+		assert_eq!(window.get_min(), 0);
+		window.push(5);
+		window.push(3);
+		window.push(4);
+		assert_eq!(window.get_min(), 3);
+		window.push(6);
+		assert_eq!(window.get_min(), 3);
+		// 3 leaves the window
+		window.push(7);
+		assert_eq!(window.get_min(), 4);
+		window.push(1);
+		assert_eq!(window.get_min(), 1);
+		assert_eq!(window.queue.len(), 1);
+	}
+
+	#[test]
+	fn sliding_window_maximum_wraps_time() {
+		let size = 10;
+		let mut window = SlidingWindowMinimum::<Reverse<u8>>::new(size);
+		// insertion time wraps around several times
+		for i in 0..1000usize {
+			window.push(Reverse((i % 37) as u8));
+			let start = i.saturating_sub(usize::from(size) - 1);
+			let max = (start..=i).map(|j| (j % 37) as u8).max().unwrap();
+			assert_eq!(window.get_min().0, max, "at {}", i);
+			assert!(window.queue.len() <= usize::from(size));
+		}
+	}
+}
@@ -0,0 +1,249 @@
+//! Discord puppets
+//!
+//! A pool of extra discord bots, each one plays a single teamspeak speaker and is named after
+//! it. Speakers without a free bot stay in the mix of the main bot.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{Read, Seek};
+use std::mem::size_of;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use byte_slice_cast::AsMutByteSlice;
+use serenity::http::Http;
+use serenity::model::id::{ChannelId, GuildId};
+use serenity::prelude::GatewayIntents;
+use serenity::Client;
+use slog::{warn, Logger};
+use songbird::driver::DecodeMode;
+use songbird::input::reader::MediaSource;
+use songbird::input::{Input, Reader};
+use songbird::{SerenityInit, Songbird};
+
+use crate::TsVoiceId;
+
+pub type DiscordPuppetsHandle = Arc<std::sync::Mutex<DiscordPuppets>>;
+
+/// Speakers keep their bot for this long after they stopped talking
+const IDLE_TIME: Duration = Duration::from_secs(30);
+/// Samples buffered per bot at most, 100ms stereo
+const MAX_BUFFERED: usize = 48 * 100 * 2;
+/// How often bots join, leave and get renamed
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A pooled bot
+struct Slot {
+	/// Assigned teamspeak speaker and its nickname
+	speaker: Option<(TsVoiceId, String)>,
+	/// Joined and playing, only then the audio of the speaker goes to this bot
+	ready: bool,
+	last_active: Instant,
+	buffer: VecDeque<f32>,
+}
+
+pub struct DiscordPuppets {
+	slots: Vec<Slot>,
+	/// Teamspeak nicknames by client ID
+	names: HashMap<u16, String>,
+	/// Guild the main bot bridges in
+	guild: Option<GuildId>,
+	/// Discord users of the bots, the main bot must not bridge them back
+	users: HashSet<u64>,
+	/// SSRCs the main bot received from them
+	ssrcs: HashSet<u32>,
+}
+
+impl DiscordPuppets {
+	pub fn new(bots: usize) -> Self {
+		let slots = (0..bots).map(|_| Slot { speaker: None, ready: false, last_active: Instant::now(), buffer: VecDeque::new() }).collect();
+		Self { slots, names: HashMap::new(), guild: None, users: HashSet::new(), ssrcs: HashSet::new() }
+	}
+
+	/// Remember the SSRC of a discord user if it is one of our bots, returns whether it is.
+	pub fn check_user(&mut self, ssrc: u32, user: u64) -> bool {
+		let puppet = self.users.contains(&user);
+		if puppet {
+			self.ssrcs.insert(ssrc);
+		}
+		puppet
+	}
+
+	/// Whether this discord user is one of our bots
+	pub fn is_puppet_user(&self, user: u64) -> bool { self.users.contains(&user) }
+
+	/// Whether this SSRC belongs to one of our bots
+	pub fn is_puppet_ssrc(&self, ssrc: u32) -> bool { self.ssrcs.contains(&ssrc) }
+
+	pub fn set_names(&mut self, names: HashMap<u16, String>) { self.names = names; }
+
+	/// Set by joining and leaving voice
+	pub fn set_guild(&mut self, guild: Option<GuildId>) { self.guild = guild; }
+
+	/// Hand the samples of an active speaker to its bot, assigns a free bot to new speakers.
+	///
+	/// Returns false if the samples have to be mixed by the main bot.
+	pub fn route(&mut self, id: &TsVoiceId, samples: &[f32]) -> bool {
+		if self.slots.is_empty() {
+			return false;
+		}
+		if let Some(slot) = self.slots.iter_mut().find(|s| s.speaker.as_ref().map(|(s, _)| s) == Some(id)) {
+			slot.last_active = Instant::now();
+			if !slot.ready {
+				return false;
+			}
+			slot.buffer.extend(samples);
+			let overflow = slot.buffer.len().saturating_sub(MAX_BUFFERED);
+			slot.buffer.drain(..overflow);
+			return true;
+		}
+		let name = match self.names.get(&(id.1).0) {
+			Some(v) => v.clone(),
+			None => return false,
+		};
+		if let Some(slot) = self.slots.iter_mut().find(|s| s.speaker.is_none()) {
+			slot.speaker = Some((*id, name));
+			slot.last_active = Instant::now();
+		}
+		false
+	}
+
+	/// Free the bots of speakers which stopped talking a while ago
+	pub fn finish_frame(&mut self) {
+		for slot in &mut self.slots {
+			if slot.speaker.is_some() && slot.last_active.elapsed() > IDLE_TIME {
+				slot.speaker = None;
+				slot.ready = false;
+				slot.buffer.clear();
+			}
+		}
+	}
+}
+
+/// Audio of a single bot
+#[derive(Clone)]
+struct SlotSource {
+	puppets: DiscordPuppetsHandle,
+	slot: usize,
+}
+
+impl Read for SlotSource {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		let mut samples = vec![0.0f32; buf.len() / size_of::<f32>()];
+		{
+			let mut puppets = self.puppets.lock().expect("Can't lock discord puppets!");
+			let buffer = &mut puppets.slots[self.slot].buffer;
+			let available = buffer.len().min(samples.len());
+			for (out, sample) in samples.iter_mut().zip(buffer.drain(..available)) {
+				*out = sample;
+			}
+		}
+		buf.copy_from_slice(samples.as_mut_byte_slice());
+		Ok(buf.len())
+	}
+}
+
+impl Seek for SlotSource {
+	fn seek(&mut self, _: std::io::SeekFrom) -> std::io::Result<u64> {
+		Err(std::io::Error::other("source does not support seeking"))
+	}
+}
+
+impl MediaSource for SlotSource {
+	fn is_seekable(&self) -> bool {
+		false
+	}
+
+	fn byte_len(&self) -> Option<u64> {
+		None
+	}
+}
+
+struct Bot {
+	http: Arc<Http>,
+	songbird: Arc<Songbird>,
+	/// Channel and nickname the bot currently has
+	channel: Option<(GuildId, ChannelId)>,
+	name: Option<String>,
+}
+
+/// Start a client for every token and keep them in sync with the assigned speakers.
+pub async fn start(logger: Logger, tokens: &[String], puppets: DiscordPuppetsHandle, bridge: Arc<Songbird>) -> Result<()> {
+	let mut bots = Vec::new();
+	for token in tokens {
+		let songbird = Songbird::serenity();
+		// puppets only send audio
+		songbird.set_config(songbird::Config::default().decode_mode(DecodeMode::Pass));
+		let mut client = Client::builder(token, GatewayIntents::GUILDS | GatewayIntents::GUILD_VOICE_STATES)
+			.register_songbird_with(songbird.clone())
+			.await?;
+		let http = client.cache_and_http.http.clone();
+		let user = http.get_current_user().await.context("Invalid discord puppet token")?;
+		puppets.lock().expect("Can't lock discord puppets!").users.insert(user.id.0);
+		let client_logger = logger.clone();
+		tokio::spawn(async move {
+			if let Err(e) = client.start().await {
+				warn!(client_logger, "Puppet client ended"; "error" => %e);
+			}
+		});
+		bots.push(Bot { http, songbird, channel: None, name: None });
+	}
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval(SYNC_INTERVAL);
+		loop {
+			interval.tick().await;
+			sync(&logger, &mut bots, &puppets, &bridge).await;
+		}
+	});
+	Ok(())
+}
+
+/// Join, move, rename or remove the bots to match their speakers
+async fn sync(logger: &Logger, bots: &mut [Bot], puppets: &DiscordPuppetsHandle, bridge: &Songbird) {
+	let guild = puppets.lock().expect("Can't lock discord puppets!").guild;
+	let target = match guild {
+		Some(guild) => match bridge.get(guild) {
+			Some(call) => call.lock().await.current_channel().map(|c| (guild, ChannelId(c.0))),
+			None => None,
+		},
+		None => None,
+	};
+	for (index, bot) in bots.iter_mut().enumerate() {
+		let speaker = puppets.lock().expect("Can't lock discord puppets!").slots[index].speaker.clone();
+		let name = speaker.as_ref().map(|(_, n)| n.clone());
+		let wanted = match (&name, target) {
+			(Some(_), Some(target)) => Some(target),
+			_ => None,
+		};
+		if bot.channel != wanted {
+			if let Some((guild, _)) = bot.channel {
+				if let Err(e) = bot.songbird.remove(guild).await {
+					warn!(logger, "Puppet failed to leave"; "bot" => index, "error" => %e);
+				}
+				bot.channel = None;
+			}
+			if let Some((guild, channel)) = wanted {
+				let (call, result) = bot.songbird.join(guild, channel).await;
+				if let Err(e) = result {
+					warn!(logger, "Puppet failed to join"; "bot" => index, "channel" => channel.0, "error" => %e);
+					continue;
+				}
+				let source = SlotSource { puppets: puppets.clone(), slot: index };
+				call.lock().await.play_only_source(Input::float_pcm(true, Reader::Extension(Box::new(source))));
+				bot.channel = wanted;
+			}
+		}
+		if let (Some(name), Some((guild, _))) = (&name, bot.channel) {
+			if bot.name.as_ref() != Some(name) {
+				if let Err(e) = guild.edit_nickname(&bot.http, Some(name)).await {
+					warn!(logger, "Puppet failed to change its nickname"; "bot" => index, "error" => %e);
+				}
+				bot.name = Some(name.clone());
+			}
+		}
+		let mut puppets = puppets.lock().expect("Can't lock discord puppets!");
+		let slot = &mut puppets.slots[index];
+		// the speaker may have changed while we were joining
+		slot.ready = bot.channel.is_some() && slot.speaker.as_ref().map(|(id, _)| id) == speaker.as_ref().map(|(id, _)| id);
+	}
+}
@@ -0,0 +1,221 @@
+//! Dynamics processing
+//!
+//! Compressor, makeup gain and limiter applied to the mixed audio of one direction,
+//! so multiple people talking at once don't clip in the opus encoder.
+
+use serde::Deserialize;
+use slog::{info, Logger};
+
+use crate::SAMPLE_RATE;
+
+/// Log gain reduction metrics every minute of audio (in frames of 20ms).
+const REPORT_FRAMES: u64 = 50 * 60;
+/// Gain reductions below this are not counted, in dB.
+const MIN_REDUCTION_DB: f32 = 0.1;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct DynamicsSettings {
+	pub discord_to_ts: DynamicsConfig,
+	pub ts_to_discord: DynamicsConfig,
+}
+
+/// Processing chain of one direction
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct DynamicsConfig {
+	pub compressor: Option<CompressorConfig>,
+	/// Gain applied after the compressor, in dB
+	pub makeup_gain_db: f32,
+	pub limiter: Option<LimiterConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct CompressorConfig {
+	pub threshold_db: f32,
+	pub ratio: f32,
+	pub attack_ms: f32,
+	pub release_ms: f32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct LimiterConfig {
+	/// Maximum output level, in dB full scale
+	pub ceiling_db: f32,
+	pub release_ms: f32,
+}
+
+impl Default for DynamicsConfig {
+	fn default() -> Self {
+		Self { compressor: None, makeup_gain_db: 0.0, limiter: Some(LimiterConfig::default()) }
+	}
+}
+
+impl Default for CompressorConfig {
+	fn default() -> Self { Self { threshold_db: -18.0, ratio: 3.0, attack_ms: 5.0, release_ms: 150.0 } }
+}
+
+impl Default for LimiterConfig {
+	fn default() -> Self { Self { ceiling_db: -1.0, release_ms: 50.0 } }
+}
+
+/// Gain reduction statistics of one direction.
+#[derive(Clone, Copy, Debug, Default)]
+struct DynamicsMetrics {
+	/// Processed frames
+	frames: u64,
+	/// Frames in which the compressor reduced the gain
+	compressed_frames: u64,
+	/// Frames in which the limiter reduced the gain
+	limited_frames: u64,
+	/// Highest compressor gain reduction, in dB
+	max_compressor_reduction_db: f32,
+	/// Highest limiter gain reduction, in dB
+	max_limiter_reduction_db: f32,
+}
+
+struct Compressor {
+	threshold_db: f32,
+	ratio: f32,
+	attack: f32,
+	release: f32,
+	/// Current gain reduction, in dB
+	reduction_db: f32,
+}
+
+struct Limiter {
+	ceiling: f32,
+	release: f32,
+	/// Current gain, linear
+	gain: f32,
+}
+
+pub struct Dynamics {
+	logger: Logger,
+	compressor: Option<Compressor>,
+	makeup_gain: f32,
+	limiter: Option<Limiter>,
+	/// Metrics since the last report
+	report: DynamicsMetrics,
+	/// Highest gain reduction of compressor and limiter together in the last frame, in dB
+	last_reduction_db: f32,
+}
+
+impl Dynamics {
+	pub fn new(logger: Logger, config: &DynamicsConfig) -> Self {
+		Self {
+			logger,
+			compressor: config.compressor.as_ref().map(|c| Compressor {
+				threshold_db: c.threshold_db,
+				ratio: c.ratio.max(1.0),
+				attack: smoothing(c.attack_ms),
+				release: smoothing(c.release_ms),
+				reduction_db: 0.0,
+			}),
+			makeup_gain: db_to_gain(config.makeup_gain_db),
+			limiter: config.limiter.as_ref().map(|l| Limiter {
+				ceiling: db_to_gain(l.ceiling_db.min(0.0)),
+				release: smoothing(l.release_ms),
+				gain: 1.0,
+			}),
+			report: Default::default(),
+			last_reduction_db: 0.0,
+		}
+	}
+
+	/// Current gain reduction, in dB
+	pub fn reduction_db(&self) -> f32 { self.last_reduction_db }
+
+	/// Process interleaved stereo samples in place.
+	pub fn process(&mut self, buf: &mut [f32]) {
+		let mut max_compressor = 0.0f32;
+		let mut max_limiter = 0.0f32;
+		let mut max_total = 0.0f32;
+		for frame in buf.chunks_exact_mut(2) {
+			let mut reduction_db = 0.0;
+			let mut gain = self.makeup_gain;
+			if let Some(compressor) = self.compressor.as_mut() {
+				let level = frame[0].abs().max(frame[1].abs());
+				let reduction = compressor.reduction(level);
+				max_compressor = max_compressor.max(reduction);
+				reduction_db += reduction;
+				gain *= db_to_gain(-reduction);
+			}
+			if let Some(limiter) = self.limiter.as_mut() {
+				let peak = frame[0].abs().max(frame[1].abs()) * gain;
+				let limit = limiter.gain(peak);
+				max_limiter = max_limiter.max(-gain_to_db(limit));
+				reduction_db -= gain_to_db(limit);
+				gain *= limit;
+			}
+			max_total = max_total.max(reduction_db);
+			frame[0] *= gain;
+			frame[1] *= gain;
+		}
+		self.last_reduction_db = max_total;
+		self.update_metrics(max_compressor, max_limiter);
+	}
+
+	fn update_metrics(&mut self, compressor: f32, limiter: f32) {
+		let metrics = &mut self.report;
+		metrics.frames += 1;
+		if compressor > MIN_REDUCTION_DB {
+			metrics.compressed_frames += 1;
+		}
+		if limiter > MIN_REDUCTION_DB {
+			metrics.limited_frames += 1;
+		}
+		metrics.max_compressor_reduction_db = metrics.max_compressor_reduction_db.max(compressor);
+		metrics.max_limiter_reduction_db = metrics.max_limiter_reduction_db.max(limiter);
+		if metrics.frames >= REPORT_FRAMES {
+			let report = std::mem::take(&mut self.report);
+			if report.compressed_frames > 0 || report.limited_frames > 0 {
+				info!(self.logger, "Gain reduction";
+					"compressed_frames" => report.compressed_frames,
+					"limited_frames" => report.limited_frames,
+					"max_compressor_db" => report.max_compressor_reduction_db,
+					"max_limiter_db" => report.max_limiter_reduction_db);
+			}
+		}
+	}
+}
+
+impl Compressor {
+	/// Update the envelope with a new sample level, returns the gain reduction in dB.
+	fn reduction(&mut self, level: f32) -> f32 {
+		let over = gain_to_db(level) - self.threshold_db;
+		let target = if over > 0.0 { over * (1.0 - 1.0 / self.ratio) } else { 0.0 };
+		let coeff = if target > self.reduction_db { self.attack } else { self.release };
+		self.reduction_db = target + coeff * (self.reduction_db - target);
+		self.reduction_db
+	}
+}
+
+impl Limiter {
+	/// Update with the peak of the next sample, returns the gain to apply.
+	///
+	/// Reacts instantly to peaks, so the output never exceeds the ceiling.
+	fn gain(&mut self, peak: f32) -> f32 {
+		let needed = if peak > self.ceiling { self.ceiling / peak } else { 1.0 };
+		if needed < self.gain {
+			self.gain = needed;
+		} else {
+			self.gain = needed + self.release * (self.gain - needed);
+		}
+		self.gain
+	}
+}
+
+/// One-pole smoothing coefficient for the given time constant.
+fn smoothing(ms: f32) -> f32 {
+	if ms <= 0.0 {
+		return 0.0;
+	}
+	(-1.0 / (ms / 1000.0 * SAMPLE_RATE as f32)).exp()
+}
+
+fn db_to_gain(db: f32) -> f32 { 10f32.powf(db / 20.0) }
+
+fn gain_to_db(gain: f32) -> f32 { 20.0 * gain.max(1e-9).log10() }
@@ -0,0 +1,87 @@
+//! Voice activity gate
+//!
+//! Mutes a speaker while their level stays below a threshold, so constant low level
+//! noise isn't forwarded and doesn't count as talking.
+
+use serde::Deserialize;
+
+use crate::{FRAME_SIZE_MS, SAMPLE_RATE};
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct GateSettings {
+	pub discord_to_ts: Option<GateConfig>,
+	pub ts_to_discord: Option<GateConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct GateConfig {
+	/// Level at which the gate opens, RMS in dB full scale
+	pub threshold_db: f32,
+	/// Fade in time when the gate opens
+	pub attack_ms: f32,
+	/// Time the gate stays open after the level dropped below the threshold
+	pub hangover_ms: f32,
+}
+
+impl Default for GateConfig {
+	fn default() -> Self { Self { threshold_db: -50.0, attack_ms: 5.0, hangover_ms: 300.0 } }
+}
+
+/// Gate state of one speaker
+pub struct VoiceGate {
+	/// Threshold as linear RMS
+	threshold: f32,
+	/// Gain increase per sample while opening
+	attack_step: f32,
+	hangover_frames: u32,
+	/// Frames left until the gate closes
+	hold: u32,
+	/// Current gain, linear
+	gain: f32,
+}
+
+impl VoiceGate {
+	pub fn new(config: &GateConfig) -> Self {
+		let attack_samples = config.attack_ms.max(0.0) / 1000.0 * SAMPLE_RATE as f32;
+		Self {
+			threshold: 10f32.powf(config.threshold_db / 20.0),
+			attack_step: if attack_samples < 1.0 { 1.0 } else { 1.0 / attack_samples },
+			hangover_frames: (config.hangover_ms.max(0.0) / FRAME_SIZE_MS as f32).round() as u32,
+			hold: 0,
+			gain: 0.0,
+		}
+	}
+
+	/// Process interleaved stereo samples in place.
+	///
+	/// Returns `false` when the gate was closed for the whole frame, the samples are silent then.
+	pub fn process(&mut self, samples: &mut [f32]) -> bool {
+		if samples.is_empty() {
+			return false;
+		}
+		let rms = (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt();
+		let above = rms >= self.threshold;
+		let open = above || self.hold > 0;
+		if above {
+			self.hold = self.hangover_frames;
+		} else {
+			self.hold = self.hold.saturating_sub(1);
+		}
+
+		let start_gain = self.gain;
+		// closing fades out over one frame to avoid clicks
+		let release_step = 2.0 / samples.len() as f32;
+		for frame in samples.chunks_exact_mut(2) {
+			self.gain = if open {
+				(self.gain + self.attack_step).min(1.0)
+			} else {
+				(self.gain - release_step).max(0.0)
+			};
+			frame[0] *= self.gain;
+			frame[1] *= self.gain;
+		}
+		start_gain > 0.0 || self.gain > 0.0
+	}
+}
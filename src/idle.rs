@@ -0,0 +1,63 @@
+//! Idle handling
+//!
+//! Leaves discord voice once no one is on either side for a while and joins the same channel
+//! again as soon as someone shows up in it or in the teamspeak channel.
+
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serenity::model::id::{ChannelId, GuildId};
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct IdleConfig {
+	/// Minutes both sides have to be empty until leaving discord voice, disabled if unset
+	pub leave_minutes: Option<u64>,
+	/// Away message of the teamspeak client while idle, not set away if unset
+	pub teamspeak_away: Option<String>,
+}
+
+pub enum IdleAction {
+	Leave(GuildId),
+	Join(GuildId, ChannelId),
+}
+
+pub struct Idle {
+	leave_after: Duration,
+	/// Since when no one is on either side
+	empty_since: Option<Instant>,
+	/// Voice channel we left because of being idle
+	left: Option<(GuildId, ChannelId)>,
+}
+
+impl Idle {
+	/// `None` if disabled
+	pub fn new(config: &IdleConfig) -> Option<Self> {
+		let minutes = config.leave_minutes?;
+		Some(Self { leave_after: Duration::from_secs(minutes * 60), empty_since: None, left: None })
+	}
+
+	/// The channel whose occupancy matters, the bridged one or the one we left
+	pub fn watched(&self, bridged: Option<(GuildId, ChannelId)>) -> Option<(GuildId, ChannelId)> { bridged.or(self.left) }
+
+	/// Decide what to do, `occupied` is whether anyone is in the watched channel or in teamspeak.
+	pub fn update(&mut self, bridged: Option<(GuildId, ChannelId)>, occupied: bool) -> Option<IdleAction> {
+		if occupied {
+			self.empty_since = None;
+			return match (bridged, self.left.take()) {
+				(None, Some((guild, channel))) => Some(IdleAction::Join(guild, channel)),
+				_ => None,
+			};
+		}
+		let (guild, channel) = bridged?;
+		// joined by a command while we waited
+		self.left = None;
+		let empty_since = *self.empty_since.get_or_insert_with(Instant::now);
+		if empty_since.elapsed() < self.leave_after {
+			return None;
+		}
+		self.empty_since = None;
+		self.left = Some((guild, channel));
+		Some(IdleAction::Leave(guild))
+	}
+}
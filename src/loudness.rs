@@ -0,0 +1,156 @@
+//! Per speaker loudness normalization
+//!
+//! Measures the momentary loudness (ITU-R BS.1770, 400ms window) of every speaker
+//! and computes a gain towards a target level, applied by the [`crate::preprocess::Preprocessor`].
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+use serde::Deserialize;
+
+use crate::FRAME_SIZE_MS;
+
+/// Frames in the momentary loudness window, 400ms.
+const WINDOW_FRAMES: usize = 400 / FRAME_SIZE_MS;
+/// Forget speakers that didn't talk for 10 minutes (in frames).
+const MAX_IDLE_FRAMES: u64 = 10 * 60 * 1000 / FRAME_SIZE_MS as u64;
+
+/// K-weighting pre filter (high shelf) at 48kHz
+const SHELF: Biquad = Biquad {
+	b: [1.535_124_8, -2.691_696_2, 1.198_392_8],
+	a: [-1.690_659_3, 0.732_480_8],
+};
+/// K-weighting RLB filter (high pass) at 48kHz
+const HIGH_PASS: Biquad = Biquad { b: [1.0, -2.0, 1.0], a: [-1.990_047_5, 0.990_072_25] };
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct LoudnessSettings {
+	pub discord_to_ts: Option<LoudnessConfig>,
+	pub ts_to_discord: Option<LoudnessConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct LoudnessConfig {
+	/// Target loudness, in LUFS
+	pub target_lufs: f32,
+	/// Maximum boost or cut, in dB
+	pub max_gain_db: f32,
+	/// Time to reduce the gain of a loud speaker
+	pub attack_ms: f32,
+	/// Time to raise the gain of a quiet speaker
+	pub release_ms: f32,
+	/// Frames quieter than this are silence and don't change the gain, in LUFS
+	pub gate_lufs: f32,
+}
+
+impl Default for LoudnessConfig {
+	fn default() -> Self {
+		Self { target_lufs: -20.0, max_gain_db: 12.0, attack_ms: 300.0, release_ms: 3000.0, gate_lufs: -50.0 }
+	}
+}
+
+#[derive(Clone, Copy)]
+struct Biquad {
+	b: [f32; 3],
+	a: [f32; 2],
+}
+
+/// Direct form II transposed state
+#[derive(Clone, Copy, Default)]
+struct FilterState([f32; 2]);
+
+impl FilterState {
+	fn process(&mut self, filter: &Biquad, x: f32) -> f32 {
+		let y = filter.b[0] * x + self.0[0];
+		self.0[0] = filter.b[1] * x - filter.a[0] * y + self.0[1];
+		self.0[1] = filter.b[2] * x - filter.a[1] * y;
+		y
+	}
+}
+
+#[derive(Default)]
+struct Speaker {
+	/// Filter states per channel
+	shelf: [FilterState; 2],
+	high_pass: [FilterState; 2],
+	/// Mean square of the last frames
+	window: VecDeque<f32>,
+	/// Current gain, in dB
+	gain_db: f32,
+	/// Frame counter of the last measurement
+	last_seen: u64,
+}
+
+/// Loudness normalization for all speakers of one direction.
+pub struct LoudnessNormalizer<Id> {
+	config: LoudnessConfig,
+	attack: f32,
+	release: f32,
+	speakers: HashMap<Id, Speaker>,
+	frame: u64,
+}
+
+impl<Id: Clone + Eq + Hash> LoudnessNormalizer<Id> {
+	pub fn new(config: LoudnessConfig) -> Self {
+		Self {
+			attack: smoothing(config.attack_ms),
+			release: smoothing(config.release_ms),
+			config,
+			speakers: Default::default(),
+			frame: 0,
+		}
+	}
+
+	/// Measure the next frame of a speaker, interleaved stereo.
+	pub fn measure(&mut self, id: &Id, samples: &[f32]) {
+		if samples.is_empty() {
+			return;
+		}
+		let speaker = self.speakers.entry(id.clone()).or_default();
+		speaker.last_seen = self.frame;
+		let mut sum = 0.0;
+		for frame in samples.chunks_exact(2) {
+			for (channel, sample) in frame.iter().enumerate() {
+				let x = speaker.shelf[channel].process(&SHELF, *sample);
+				let x = speaker.high_pass[channel].process(&HIGH_PASS, x);
+				sum += x * x;
+			}
+		}
+		speaker.window.push_back(sum / (samples.len() / 2) as f32);
+		if speaker.window.len() > WINDOW_FRAMES {
+			speaker.window.pop_front();
+		}
+
+		let mean = speaker.window.iter().sum::<f32>() / speaker.window.len() as f32;
+		let loudness = -0.691 + 10.0 * mean.max(1e-12).log10();
+		if loudness < self.config.gate_lufs {
+			return;
+		}
+		let target = (self.config.target_lufs - loudness)
+			.clamp(-self.config.max_gain_db, self.config.max_gain_db);
+		let coeff = if target < speaker.gain_db { self.attack } else { self.release };
+		speaker.gain_db = target + coeff * (speaker.gain_db - target);
+	}
+
+	/// Linear gain for a speaker
+	pub fn gain(&self, id: &Id) -> f32 {
+		self.speakers.get(id).map(|s| 10f32.powf(s.gain_db / 20.0)).unwrap_or(1.0)
+	}
+
+	/// Call once per mixed frame, forgets speakers that are gone for too long.
+	pub fn tick(&mut self) {
+		self.frame += 1;
+		let frame = self.frame;
+		self.speakers.retain(|_, s| frame - s.last_seen < MAX_IDLE_FRAMES);
+	}
+}
+
+/// One-pole smoothing coefficient per frame for the given time constant.
+fn smoothing(ms: f32) -> f32 {
+	if ms <= 0.0 {
+		return 0.0;
+	}
+	(-(FRAME_SIZE_MS as f32) / ms).exp()
+}
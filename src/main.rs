@@ -1,20 +1,58 @@
 use std::io::Seek;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::{io::Read, mem::size_of, sync::Arc, time::Duration};
 use byte_slice_cast::AsByteSlice;
 use serde::Deserialize;
+use serenity::model::id::{ChannelId, GuildId, UserId};
 use serenity::prelude::GatewayIntents;
 use songbird::input::reader::MediaSource;
-use tsclientlib::{ClientId, Connection, DisconnectOptions, Identity, StreamItem};
+use tsclientlib::events::Event;
+use tsclientlib::{ClientId, Connection, DisconnectOptions, Identity, MessageTarget, StreamItem};
+use tsclientlib::prelude::*;
 use tsproto_packets::packets::{AudioData, CodecType, OutAudio, OutPacket};
 use audiopus::coder::Encoder;
 use futures::prelude::*;
-use slog::{debug, o, Drain, Logger};
+use slog::{debug, o, warn, Drain, Logger};
 use tokio::task;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, oneshot, Mutex};
 use anyhow::{bail,Result};
 
 mod discord;
 mod discord_audiohandler;
+mod discord_puppet;
+mod access;
+mod capture;
+mod denoise;
+mod dynamics;
+mod gate;
+mod idle;
+mod loudness;
+mod permissions;
+mod preprocess;
+mod presence;
+mod puppet;
+mod recorder;
+mod replay;
+mod roster;
+mod soundboard;
+mod speaker_display;
+mod state;
+mod status;
+
+use access::AccessHandle;
+use capture::{Capture, CaptureHandle};
+use discord::SpeakerHandle;
+use discord_puppet::{DiscordPuppets, DiscordPuppetsHandle};
+use preprocess::Preprocessor;
+use recorder::{RecorderHandle, Source, Track};
+use replay::ReplayHandle;
+use roster::{RosterHandle, TsChannel, TsMember};
+use soundboard::SoundboardHandle;
+use speaker_display::{DisplayMode, SpeakerDisplay};
+use state::{StateHandle, StateStore, TsChannelChoice, UserSettings};
+use status::{format_duration, BridgeStatus, Direction, LossCounter, StatusHandle, TsConnection, MAX_PAUSE_MINUTES};
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 struct ConnectionId(u64);
@@ -40,6 +78,11 @@ use serenity::{
 #[derive(Debug,Deserialize)]
 struct Config {
     discord_token: String,
+	/// guilds to register slash commands in, global if empty
+	#[serde(default)]
+	discord_guilds: Vec<u64>,
+	/// legacy `~` prefix commands, default true
+	prefix_commands: Option<bool>,
     teamspeak_server: String,
     teamspeak_identity: String,
 	teamspeak_server_password: Option<String>,
@@ -50,7 +93,46 @@ struct Config {
     /// default 0
     verbose: i32,
     /// default 1.0
+    #[allow(dead_code)]
     volume: f32,
+	/// directory for recordings, default "recordings"
+	recording_path: Option<String>,
+	/// directory for packet captures, disabled if unset
+	capture_path: Option<String>,
+	/// seconds kept for /clip, default 60
+	replay_seconds: Option<usize>,
+	/// directory for clips, default "clips"
+	clip_path: Option<String>,
+	/// directory of the soundboard files, default "sounds"
+	soundboard_path: Option<String>,
+	/// compressor & limiter per direction, defaults to a limiter only
+	#[serde(default)]
+	dynamics: dynamics::DynamicsSettings,
+	#[serde(default)]
+	loudness: loudness::LoudnessSettings,
+	#[serde(default)]
+	noise_suppression: denoise::NoiseSuppressionSettings,
+	#[serde(default)]
+	voice_gate: gate::GateSettings,
+	#[serde(default)]
+	access: access::AccessConfig,
+	#[serde(default)]
+	permissions: permissions::PermissionConfig,
+	/// text channel for a pinned roster of both sides
+	roster_channel: Option<u64>,
+	/// file for state changed by commands, default "bridge_state.toml"
+	state_path: Option<String>,
+	#[serde(default)]
+	puppets: puppet::PuppetConfig,
+	/// Teamspeak state in the discord activity and a channel topic
+	#[serde(default)]
+	presence: presence::PresenceConfig,
+	/// Leave discord voice while no one is around
+	#[serde(default)]
+	idle: idle::IdleConfig,
+	/// Discord speakers in our teamspeak nickname or description
+	#[serde(default)]
+	speaker_display: speaker_display::SpeakerDisplayConfig,
 }
 
 struct ListenerHolder;
@@ -65,6 +147,23 @@ type TsAudioHandler = tsclientlib::audio::AudioHandler<TsVoiceId>;
 #[derive(Clone)]
 struct TsToDiscordPipeline {
 	data: Arc<std::sync::Mutex<TsAudioHandler>>,
+	recorder: RecorderHandle,
+	replay: ReplayHandle,
+	soundboard: SoundboardHandle,
+	dynamics: Arc<std::sync::Mutex<dynamics::Dynamics>>,
+	preprocessor: Arc<std::sync::Mutex<Preprocessor<TsVoiceId>>>,
+	status: StatusHandle,
+	puppets: DiscordPuppetsHandle,
+	/// Volume of clients from their user settings
+	volumes: Arc<std::sync::Mutex<HashMap<ClientId, f32>>>,
+}
+
+impl TsToDiscordPipeline {
+	/// Teamspeak clients with queued audio
+	fn talking_clients(&self) -> HashSet<u16> {
+		let lock = self.data.lock().expect("Can't lock ts voice buffer!");
+		lock.get_queues().keys().map(|(_, client)| client.0).collect()
+	}
 }
 
 impl MediaSource for TsToDiscordPipeline {
@@ -79,14 +178,23 @@ impl MediaSource for TsToDiscordPipeline {
 
 impl Seek for TsToDiscordPipeline {
     fn seek(&mut self, _: std::io::SeekFrom) -> std::io::Result<u64> {
-        Err(std::io::Error::new(std::io::ErrorKind::Other, "source does not support seeking"))
+        Err(std::io::Error::other("source does not support seeking"))
     }
 }
 
 impl TsToDiscordPipeline {
-	pub fn new(logger: Logger) -> Self {
+	#[allow(clippy::too_many_arguments)]
+	pub fn new(logger: Logger, recorder: RecorderHandle, replay: ReplayHandle, soundboard: SoundboardHandle, dynamics: dynamics::Dynamics, preprocessor: Preprocessor<TsVoiceId>, status: StatusHandle, puppets: DiscordPuppetsHandle) -> Self {
 		Self {
-			data: Arc::new(std::sync::Mutex::new(TsAudioHandler::new(logger)))
+			data: Arc::new(std::sync::Mutex::new(TsAudioHandler::new(logger))),
+			recorder,
+			replay,
+			soundboard,
+			dynamics: Arc::new(std::sync::Mutex::new(dynamics)),
+			preprocessor: Arc::new(std::sync::Mutex::new(preprocessor)),
+			status,
+			puppets,
+			volumes: Default::default(),
 		}
 	}
 }
@@ -95,14 +203,64 @@ impl Read for TsToDiscordPipeline {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
 		let len = buf.len() / size_of::<f32>();
 		let mut wtr: Vec<f32> = vec![0.0; len];
+		let paused = {
+			let mut status = self.status.lock().expect("Can't lock status!");
+			status.paused.expire();
+			status.paused.ts_to_discord
+		};
 		// TODO: can't we support async read for songbird ? this is kinda bad as it requires a sync mutex
 		{
 			let mut lock = self.data.lock().expect("Can't lock ts voice buffer!");
+			// drop everything buffered, so nothing old plays after resuming
+			if paused {
+				lock.reset();
+			}
 
 			// and this is really ugly.. read only works for u8, but we get an f32 and need to convert that without changing AudioHandlers API
 			// also Read for stuff that specifies to use f32 is kinda meh			
-			lock.fill_buffer(wtr.as_mut_slice());
+			let recorder = self.recorder.lock().expect("Can't lock recorder!");
+			let mut preprocessor = self.preprocessor.lock().expect("Can't lock preprocessor!");
+			// tsclientlib doesn't allow modifying the samples before mixing,
+			// so we mix the processed samples ourselves and discard its output
+			let mut unprocessed: Vec<f32> = vec![0.0; len];
+			let mut samples = Vec::with_capacity(len);
+			let mut puppets = self.puppets.lock().expect("Can't lock discord puppets!");
+			let volumes = self.volumes.lock().expect("Can't lock ts volumes!");
+			let mut speakers = 0;
+			lock.fill_buffer_with_proc(unprocessed.as_mut_slice(), |id, data| {
+				samples.clear();
+				samples.extend_from_slice(data);
+				// gated speakers are left out of the mix
+				if preprocessor.process(id, &mut samples) {
+					speakers += 1;
+					// after the normalization, which would undo it otherwise
+					if let Some(volume) = volumes.get(&id.1).filter(|v| **v != 1.0) {
+						samples.iter_mut().for_each(|s| *s *= volume);
+					}
+					// speakers with their own bot are played by it
+					if !puppets.route(id, &samples) {
+						for (out, sample) in wtr.iter_mut().zip(&samples) {
+							*out += sample;
+						}
+					}
+					recorder.push(Track::Teamspeak(*id), Source::Teamspeak, &samples)
+				}
+			});
+			puppets.finish_frame();
+			let duration = preprocessor.finish_frame().as_millis();
+			let mut status = self.status.lock().expect("Can't lock status!");
+			status.ts_to_discord.active_speakers = speakers;
+			if duration > 2 {
+				status.warn(format!("Took {}ms for preprocessing teamspeak audio!",duration));
+			}
+			recorder.push(Track::Mix, Source::Teamspeak, &wtr);
 		}
+		self.replay.lock().expect("Can't lock replay buffer!").push(Source::Teamspeak, &wtr);
+		// already part of the other direction for recordings
+		self.soundboard.lock().expect("Can't lock soundboard!").mix_discord(&mut wtr);
+		let mut dynamics = self.dynamics.lock().expect("Can't lock dynamics!");
+		dynamics.process(&mut wtr);
+		self.status.lock().expect("Can't lock status!").ts_to_discord.gain_reduction_db = dynamics.reduction_db();
 		let slice = wtr.as_byte_slice();
 		buf.copy_from_slice(slice);
 
@@ -114,6 +272,108 @@ impl TypeMapKey for ListenerHolder {
     type Value = (TsToDiscordPipeline,AudioBufferDiscord);
 }
 
+/// Actions for the teamspeak connection, issued from the discord side.
+#[derive(Debug)]
+enum TsCommand {
+	/// Show or hide the recording indicator of our client
+	Recording(bool),
+	/// Move our client to another channel, remembered across restarts once it arrived
+	MoveChannel { id: u64, password: Option<String>, reply: oneshot::Sender<Result<String>> },
+	/// Follow the client with this nickname between channels, stop following with `None`
+	Follow { name: Option<String>, reply: oneshot::Sender<Result<String>> },
+	/// Set our client away with this message, back with `None`
+	Away(Option<String>),
+}
+
+/// A channel move which is saved when our client arrives in the channel
+struct PendingMove {
+	choice: TsChannelChoice,
+	reply: oneshot::Sender<Result<String>>,
+	started: std::time::Instant,
+}
+
+/// Time for the server to move us, wrong passwords or deleted channels never do
+const MOVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct TsCommandHolder;
+
+impl TypeMapKey for TsCommandHolder {
+	type Value = mpsc::UnboundedSender<TsCommand>;
+}
+
+struct RecorderHolder;
+
+impl TypeMapKey for RecorderHolder {
+	type Value = RecorderHandle;
+}
+
+struct ReplayHolder;
+
+impl TypeMapKey for ReplayHolder {
+	type Value = ReplayHandle;
+}
+
+struct SoundboardHolder;
+
+impl TypeMapKey for SoundboardHolder {
+	type Value = SoundboardHandle;
+}
+
+struct AccessHolder;
+
+impl TypeMapKey for AccessHolder {
+	type Value = AccessHandle;
+}
+
+struct StatusHolder;
+
+impl TypeMapKey for StatusHolder {
+	type Value = StatusHandle;
+}
+
+struct FollowHolder;
+
+impl TypeMapKey for FollowHolder {
+	/// Followed discord user per guild
+	type Value = Arc<std::sync::Mutex<HashMap<GuildId, UserId>>>;
+}
+
+struct DiscordPuppetsHolder;
+
+impl TypeMapKey for DiscordPuppetsHolder {
+	type Value = DiscordPuppetsHandle;
+}
+
+struct StateHolder;
+
+impl TypeMapKey for StateHolder {
+	type Value = StateHandle;
+}
+
+struct CaptureHolder;
+
+impl TypeMapKey for CaptureHolder {
+	type Value = CaptureHandle;
+}
+
+struct SpeakersHolder;
+
+impl TypeMapKey for SpeakersHolder {
+	type Value = SpeakerHandle;
+}
+
+struct RosterHolder;
+
+impl TypeMapKey for RosterHolder {
+	type Value = RosterHandle;
+}
+
+struct PermissionsHolder;
+
+impl TypeMapKey for PermissionsHolder {
+	type Value = Arc<permissions::CommandPermissions>;
+}
+
 /// teamspeak audio fragment timer
 /// We want to run every 20ms, but we only get ~1ms correctness
 const TICK_TIME: u64 = 20;
@@ -123,9 +383,13 @@ const STEREO_20MS: usize = SAMPLE_RATE * 2 * FRAME_SIZE_MS / 1000;
 /// The maximum size of an opus frame is 1275 as from RFC6716.
 const MAX_OPUS_FRAME_SIZE: usize = 1275;
 
-const RUST_LOG: &'static str = "RUST_LOG";
+const RUST_LOG: &str = "RUST_LOG";
 #[tokio::main]
 async fn main() -> Result<()> {
+	let args: Vec<String> = std::env::args().collect();
+	if args.get(1).map(String::as_str) == Some("replay") {
+		return capture::replay_command(&args[2..]);
+	}
 	if std::env::var(RUST_LOG).is_err() {
         std::env::set_var(
             RUST_LOG,
@@ -150,7 +414,9 @@ async fn main() -> Result<()> {
     let framework = StandardFramework::new()
         .configure(|c| c
                    .prefix("~"))
+        .before(discord::check_permission)
         .group(&discord::GENERAL_GROUP);
+	let command_permissions = Arc::new(permissions::CommandPermissions::new(&config.permissions)?);
 
 	// Here, we need to configure Songbird to decode all incoming voice packets.
     // If you want, you can do this on a per-call basis---here, we need it to
@@ -160,26 +426,96 @@ async fn main() -> Result<()> {
         DriverConfig::default()
             .decode_mode(DecodeMode::Decrypt)
     );
+	let bridge_songbird = songbird.clone();
 
-	let intents = GatewayIntents::GUILD_MESSAGES
-        | GatewayIntents::MESSAGE_CONTENT
+	// guilds for the role and permission cache
+	let mut intents = GatewayIntents::GUILDS
 		| GatewayIntents::GUILD_VOICE_STATES;
+	let prefix_commands = config.prefix_commands.unwrap_or(true);
+	if prefix_commands {
+		intents |= GatewayIntents::GUILD_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
+	}
 
 	// init discord client
+	let handler = discord::Handler {
+		guilds: config.discord_guilds.iter().map(|g| GuildId(*g)).collect(),
+		roster_channel: config.roster_channel.map(ChannelId),
+		roster_started: Default::default(),
+		presence: config.presence.clone(),
+		presence_started: Default::default(),
+		idle: config.idle.clone(),
+		idle_started: Default::default(),
+	};
     let mut client = Client::builder(&config.discord_token, intents)
-        .event_handler(discord::Handler)
-        .framework(framework)
-        .register_songbird_with(songbird.into())
+        .event_handler(handler)
+        .register_songbird_with(songbird);
+	if prefix_commands {
+		client = client.framework(framework);
+	}
+	let mut client = client
         .await
         .expect("Err creating client");
 
+	// init recorder, shared by both pipelines
+	let recording_path = PathBuf::from(config.recording_path.as_deref().unwrap_or("recordings"));
+	let recorder: RecorderHandle = Arc::new(std::sync::Mutex::new(recorder::Recorder::new(logger.new(o!("pipeline" => "recorder")), recording_path)));
+
+	// received packets of both sides, for replaying audio problems
+	let capture = match &config.capture_path {
+		Some(path) => {
+			let (capture, file) = Capture::start(&PathBuf::from(path))?;
+			println!("Capturing packets to {}", file.display());
+			capture
+		},
+		None => Capture::disabled(),
+	};
+	let capture: CaptureHandle = Arc::new(std::sync::Mutex::new(capture));
+
+	// init instant replay buffer, shared by both pipelines
+	let clip_path = PathBuf::from(config.clip_path.as_deref().unwrap_or("clips"));
+	let replay: ReplayHandle = Arc::new(std::sync::Mutex::new(replay::ReplayBuffer::new(config.replay_seconds.unwrap_or(60), clip_path)));
+
+	// init soundboard, played into both pipelines
+	let soundboard_path = PathBuf::from(config.soundboard_path.as_deref().unwrap_or("sounds"));
+	let soundboard: SoundboardHandle = Arc::new(std::sync::Mutex::new(soundboard::Soundboard::new(soundboard_path)));
+
+	let status: StatusHandle = Arc::new(std::sync::Mutex::new(BridgeStatus::new()));
+	let state_path = PathBuf::from(config.state_path.as_deref().unwrap_or("bridge_state.toml"));
+	let store: StateHandle = Arc::new(std::sync::Mutex::new(StateStore::load(state_path)?));
+	let roster: RosterHandle = Default::default();
+	let speakers: SpeakerHandle = Default::default();
+	let discord_puppets: DiscordPuppetsHandle = Arc::new(std::sync::Mutex::new(DiscordPuppets::new(config.puppets.discord_tokens.len())));
+	if !config.puppets.discord_tokens.is_empty() {
+		discord_puppet::start(logger.new(o!("pipeline" => "discord-puppets")), &config.puppets.discord_tokens, discord_puppets.clone(), bridge_songbird).await?;
+	}
+
+	// whose audio crosses the bridge, rules changed by /access win over the config
+	let access_config = store.lock().expect("Can't lock state!").state().access.clone().unwrap_or_else(|| config.access.clone());
+	let access: AccessHandle = Arc::new(std::sync::Mutex::new(access::AccessList::new(access_config)));
+
 	// init teamspeak -> discord pipeline
 	let ts_voice_logger = logger.new(o!("pipeline" => "voice-ts"));
-	let teamspeak_voice_handler = TsToDiscordPipeline::new(ts_voice_logger);
+	let ts_dynamics = dynamics::Dynamics::new(ts_voice_logger.new(o!("dynamics" => "ts_to_discord")), &config.dynamics.ts_to_discord);
+	let ts_preprocessor = Preprocessor::new(config.noise_suppression.ts_to_discord.clone(), config.voice_gate.ts_to_discord.clone(), config.loudness.ts_to_discord.clone());
+	let teamspeak_voice_handler = TsToDiscordPipeline::new(ts_voice_logger, recorder.clone(), replay.clone(), soundboard.clone(), ts_dynamics, ts_preprocessor, status.clone(), discord_puppets.clone());
 
 	// init discord -> teamspeak pipeline
 	let discord_voice_logger = logger.new(o!("pipeline" => "voice-discord"));
+	let mut discord_pipeline = DiscordToTsPipeline {
+		dynamics: dynamics::Dynamics::new(discord_voice_logger.new(o!("dynamics" => "discord_to_ts")), &config.dynamics.discord_to_ts),
+		preprocessor: Preprocessor::new(config.noise_suppression.discord_to_ts.clone(), config.voice_gate.discord_to_ts.clone(), config.loudness.discord_to_ts.clone()),
+		talking: false,
+		muted: false,
+		volume: 1.0,
+		status: status.clone(),
+		speakers: speakers.clone(),
+		mixed: Vec::new(),
+		puppets: puppet::TsPuppets::new(discord_voice_logger.new(o!("puppets" => "teamspeak")), config.puppets.clone(), config.teamspeak_server.clone(), config.teamspeak_server_password.clone()),
+	};
 	let discord_voice_buffer: AudioBufferDiscord = Arc::new(Mutex::new(discord_audiohandler::AudioHandler::new(discord_voice_logger)));
+
+	// commands for the teamspeak connection from discord
+	let (ts_command_sender, mut ts_commands) = mpsc::unbounded_channel();
 	// stuff discord -> teamspeak pipeline into discord context for retrieval inside the client
 	{
 		// Open the data lock in write mode, so keys can be inserted to it.
@@ -189,6 +525,19 @@ async fn main() -> Result<()> {
 		// Arc<RwLock<HashMap<String, u64>>>
 		// So, we have to insert the same type to it.
 		data.insert::<ListenerHolder>((teamspeak_voice_handler.clone(),discord_voice_buffer.clone()));
+		data.insert::<TsCommandHolder>(ts_command_sender);
+		data.insert::<RecorderHolder>(recorder.clone());
+		data.insert::<ReplayHolder>(replay.clone());
+		data.insert::<SoundboardHolder>(soundboard.clone());
+		data.insert::<AccessHolder>(access.clone());
+		data.insert::<PermissionsHolder>(command_permissions);
+		data.insert::<StatusHolder>(status.clone());
+		data.insert::<RosterHolder>(roster.clone());
+		data.insert::<FollowHolder>(Arc::new(std::sync::Mutex::new(follow_targets(store.lock().expect("Can't lock state!").state()))));
+		data.insert::<StateHolder>(store.clone());
+		data.insert::<CaptureHolder>(capture.clone());
+		data.insert::<SpeakersHolder>(speakers.clone());
+		data.insert::<DiscordPuppetsHolder>(discord_puppets.clone());
 	}
 
 	// spawn client runner
@@ -216,9 +565,16 @@ async fn main() -> Result<()> {
 	if let Some(password) = config.teamspeak_server_password {
 		con_config = con_config.password(password);
 	}
-	if let Some(password) = config.teamspeak_channel_password {
+	if let Some(password) = config.teamspeak_channel_password.clone() {
 		con_config = con_config.channel_password(password);
 	}
+	// the channel chosen by /ts_channel wins over the config
+	if let Some(choice) = store.lock().expect("Can't lock state!").state().ts_channel.clone() {
+		con_config = con_config.channel_id(tsclientlib::ChannelId(choice.id));
+		if let Some(password) = choice.password {
+			con_config = con_config.channel_password(password);
+		}
+	}
 
 	// teamspeak: Optionally set the key of this client, otherwise a new key is generated.
 	let id = Identity::new_from_str(&config.teamspeak_identity).expect("Can't load identity!");
@@ -246,24 +602,190 @@ async fn main() -> Result<()> {
 	// we have to stuff this inside an arc-mutex to avoid lifetime shenanigans
 	let encoder = Arc::new(Mutex::new(encoder));
 
+	let own_name = {
+		let state = con.get_state()?;
+		state.clients.get(&state.own_client).map(|c| c.name.clone()).unwrap_or_default()
+	};
+	let mut speaker_display = SpeakerDisplay::new(&config.speaker_display, own_name);
+
 	// teamspeak playback timer
 	let mut interval = tokio::time::interval(Duration::from_millis(TICK_TIME));
+
+	// teamspeak clients whose audio isn't bridged, updated on changes of clients, rules or settings
+	let mut ts_blocked: HashSet<ClientId> = HashSet::new();
+	// our own puppets, their audio came from discord and they aren't members
+	let mut ts_puppets: HashSet<ClientId> = HashSet::new();
+	let ts_book_changed = Cell::new(true);
+	let mut access_generation = None;
+	let mut store_generation = None;
+	let ts_loss = RefCell::new(LossCounter::default());
+	// chat commands received by teamspeak, answered outside of the event stream
+	let ts_chat: RefCell<Vec<TsChatMessage>> = Default::default();
+	let ts_permissions = config.permissions.teamspeak.clone();
+	// followed teamspeak client and the channel we last moved to for it
+	let mut ts_follow: Option<ClientId> = None;
+	let mut ts_follow_moved = None;
+	let mut pending_move: Option<PendingMove> = None;
+	// follow the client of the last run again
+	if let Some(uid) = store.lock().expect("Can't lock state!").state().ts_follow.clone() {
+		let state = con.get_state()?;
+		ts_follow = state.clients.values().find(|c| client_uid(c) == uid).map(|c| c.id);
+	}
 	
 	loop {
+		for message in ts_chat.take() {
+			if let Err(e) = handle_ts_chat(&mut con, &mut discord_pipeline, &roster, &ts_permissions, message) {
+				warn!(logger, "Failed to answer teamspeak chat command"; "error" => %e);
+			}
+		}
+		let mut follow_move = None;
+		{
+			let access = access.lock().expect("Can't lock access list!");
+			let book_changed = ts_book_changed.replace(false);
+			let current_store = store.lock().expect("Can't lock state!").generation();
+			if book_changed || access_generation != Some(access.generation()) || store_generation != Some(current_store) {
+				access_generation = Some(access.generation());
+				store_generation = Some(current_store);
+				let state = con.get_state()?;
+				ts_puppets = state.clients.values()
+					.filter(|c| discord_pipeline.puppets.is_puppet(&client_uid(c)))
+					.map(|c| c.id)
+					.collect();
+				let settings: HashMap<ClientId, UserSettings> = {
+					let store = store.lock().expect("Can't lock state!");
+					state.clients.values().map(|c| (c.id, store.state().ts_user(&client_uid(c)))).collect()
+				};
+				ts_blocked = state.clients.values()
+					.filter(|c| {
+						let groups: Vec<u64> = c.server_groups.iter().map(|g| g.0).collect();
+						!access.teamspeak_allowed(c.database_id.0, &groups) || settings[&c.id].muted
+					})
+					.map(|c| c.id)
+					.collect();
+				// also applies to clients already talking
+				*teamspeak_voice_handler.volumes.lock().expect("Can't lock ts volumes!") = settings.iter().map(|(id, s)| (*id, s.gain())).collect();
+				if book_changed {
+					let own_channel = state.clients.get(&state.own_client).map(|c| c.channel);
+					match pending_move.take() {
+						Some(pending) if own_channel.map(|c| c.0) == Some(pending.choice.id) => {
+							let PendingMove { choice, reply, .. } = pending;
+							let path = channel_path(state, tsclientlib::ChannelId(choice.id));
+							let result = store.lock().expect("Can't lock state!")
+								.update(|s| s.ts_channel = Some(choice))
+								.map(|_| format!("Moved teamspeak to {}", path));
+							let _ = reply.send(result);
+						},
+						other => pending_move = other,
+					}
+					let channel = own_channel
+						.and_then(|c| state.channels.get(&c))
+						.map(|c| c.name.clone())
+						.unwrap_or_default();
+					let members = state.clients.values()
+						.filter(|c| Some(c.channel) == own_channel && c.id != state.own_client && !ts_puppets.contains(&c.id))
+						.map(|c| TsMember {
+							id: c.id.0,
+							uid: client_uid(c),
+							name: c.name.clone(),
+							input_muted: c.input_muted,
+							output_muted: c.output_muted,
+							away: c.away_message.clone(),
+						})
+						.collect();
+					let channels = state.channels.values()
+						.map(|c| TsChannel { id: c.id.0, path: channel_path(state, c.id), has_password: c.has_password.unwrap_or_default() })
+						.collect();
+					if let Some(channel) = own_channel {
+						// puppets use the password we joined this channel with
+						let password = match store.lock().expect("Can't lock state!").state().ts_channel.clone() {
+							Some(choice) if choice.id == channel.0 => choice.password,
+							_ => config.teamspeak_channel_password.clone(),
+						};
+						discord_pipeline.puppets.set_channel(channel, password);
+					}
+					{
+						// name the recording tracks of teamspeak speakers
+						let mut recorder = recorder.lock().expect("Can't lock recorder!");
+						for client in state.clients.values() {
+							recorder.set_label(Track::Teamspeak((con_id, client.id)), client.name.clone());
+						}
+					}
+					discord_puppets.lock().expect("Can't lock discord puppets!")
+						.set_names(state.clients.values().map(|c| (c.id.0, c.name.clone())).collect());
+					let mut roster = roster.lock().expect("Can't lock roster!");
+					roster.set_teamspeak(channel.clone(), members);
+					roster.set_ts_channels(channels);
+
+					if let Some(target) = ts_follow {
+						match state.clients.get(&target) {
+							Some(client) if Some(client.channel) != own_channel => follow_move = Some(client.channel),
+							Some(_) => ts_follow_moved = None,
+							None => {
+								println!("Followed teamspeak client left, stopped following");
+								ts_follow = None;
+								if let Err(e) = store.lock().expect("Can't lock state!").update(|s| s.ts_follow = None) {
+									warn!(logger, "Failed to save state"; "error" => %e);
+								}
+							},
+						}
+					}
+					status.lock().expect("Can't lock status!").teamspeak = Some(TsConnection { server: state.server.name.clone(), channel });
+				}
+			}
+		}
+		if pending_move.as_ref().map(|p| p.started.elapsed() > MOVE_TIMEOUT).unwrap_or_default() {
+			if let Some(pending) = pending_move.take() {
+				let _ = pending.reply.send(Err(anyhow::anyhow!("Teamspeak didn't move to the channel, is the password right?")));
+			}
+		}
+		// don't retry a failed move until the followed client moves again
+		if let Some(channel) = follow_move {
+			if ts_follow_moved != Some(channel) {
+				ts_follow_moved = Some(channel);
+				if let Err(e) = move_client(&mut con, channel, None) {
+					warn!(logger, "Failed to follow teamspeak client"; "error" => %e);
+				}
+			}
+		}
 		// handle teamspeak events
 		let events = con.events().try_for_each(|e| async {
+			if let StreamItem::BookEvents(events) = &e {
+				ts_book_changed.set(true);
+				for event in events {
+					if let Event::Message { target, invoker, message } = event {
+						if message.starts_with('!') {
+							// answer private messages privately
+							let reply = match target {
+								MessageTarget::Client(_) | MessageTarget::Poke(_) => MessageTarget::Client(invoker.id),
+								target => *target,
+							};
+							ts_chat.borrow_mut().push(TsChatMessage { reply, invoker: invoker.id, text: message.clone() });
+						}
+					}
+				}
+			}
 			// handle teamspeak audio packets
 			if let StreamItem::Audio(packet) = e {
-				let from = ClientId(match packet.data().data() {
-					AudioData::S2C { from, .. } => *from,
-					AudioData::S2CWhisper { from, .. } => *from,
+				let (id, from) = match packet.data().data() {
+					AudioData::S2C { id, from, .. } => (*id, ClientId(*from)),
+					AudioData::S2CWhisper { id, from, .. } => (*id, ClientId(*from)),
 					_ => panic!("Can only handle S2C packets but got a C2S packet"),
-				});
+				};
+				// the bridge hears the discord speakers it plays through puppets
+				if ts_puppets.contains(&from) {
+					return Ok(());
+				}
+				if ts_blocked.contains(&from) {
+					return Ok(());
+				}
 				
+				capture.lock().expect("Can't lock capture!").push(Source::Teamspeak, u32::from(from.0), id, 0, packet.raw_data());
 				let mut ts_voice: std::sync::MutexGuard<TsAudioHandler> = teamspeak_voice_handler.data.lock().expect("Can't lock ts audio buffer!");
 				// feed mixer+jitter buffer, consumed by discord
-				if let Err(e) = ts_voice.handle_packet((con_id, from), packet) {
-					debug!(logger, "Failed to handle TS_Voice packet"; "error" => %e);
+				// only accepted packets count, duplicates and late ones don't
+				match ts_voice.handle_packet((con_id, from), packet) {
+					Ok(_) => ts_loss.borrow_mut().record(from, id),
+					Err(e) => debug!(logger, "Failed to handle TS_Voice packet"; "error" => %e),
 				}
 			}
 			Ok(())
@@ -273,22 +795,46 @@ async fn main() -> Result<()> {
 			_send = interval.tick() => {
 				let start = std::time::Instant::now();
 				// send audio frame to teamspeak
-				if let Some(processed) = process_discord_audio(&discord_voice_buffer,&encoder).await {
+				if let Some(processed) = process_discord_audio(&discord_voice_buffer,&encoder,&recorder,&replay,&soundboard,&mut discord_pipeline).await {
 					con.send_audio(processed)?;
 					let dur = start.elapsed();
 					if dur >= Duration::from_millis(1) {
-						eprintln!("Audio pipeline took {}ms",dur.as_millis());
+						status.lock().expect("Can't lock status!").warn(format!("Audio pipeline took {}ms",dur.as_millis()));
+					}
+				}
+				if let Some(display) = &mut speaker_display {
+					if let Err(e) = show_speakers(&mut con, display, &speakers, &discord_pipeline.mixed) {
+						warn!(logger, "Failed to show discord speakers"; "error" => %e);
 					}
 				}
+				let loss = ts_loss.borrow();
+				let mut status = status.lock().expect("Can't lock status!");
+				status.ts_to_discord.received_packets = loss.received;
+				status.ts_to_discord.lost_packets = loss.lost;
+			}
+			Some(command) = ts_commands.recv() => {
+				// also finishes moves to the channel we are in already
+				if let TsCommand::Follow { .. } | TsCommand::MoveChannel { .. } = &command {
+					ts_book_changed.set(true);
+				}
+				if let Err(e) = handle_ts_command(&mut con, &store, &mut ts_follow, &mut pending_move, command) {
+					warn!(logger, "Failed to run teamspeak command"; "error" => %e);
+				}
 			}
 			_ = tokio::signal::ctrl_c() => { break; }
 			r = events => {
+				status.lock().expect("Can't lock status!").teamspeak = None;
 				r?;
 				bail!("Disconnected");
 			}
 		};
 	}
+	if let Some(session) = recorder.lock().expect("Can't lock recorder!").stop() {
+		println!("Finished recording {}", session.finish().display());
+	}
+	capture.lock().expect("Can't lock capture!").finish()?;
 	println!("Disconnecting");
+	status.lock().expect("Can't lock status!").teamspeak = None;
 	// Disconnect
 	con.disconnect(DisconnectOptions::new())?;
 	con.events().for_each(|_| future::ready(())).await;
@@ -297,9 +843,238 @@ async fn main() -> Result<()> {
 }
 
 
+/// Run a command from the discord side on the teamspeak connection.
+fn handle_ts_command(con: &mut Connection, state: &StateHandle, follow: &mut Option<ClientId>, pending_move: &mut Option<PendingMove>, command: TsCommand) -> Result<()> {
+	match command {
+		TsCommand::Recording(recording) => {
+			let state = con.get_state()?;
+			let update = state.client_update().set_is_recording(recording);
+			let message = if recording { "Recording started" } else { "Recording stopped" };
+			let message = state.send_message(MessageTarget::Channel, message);
+			update.send(con)?;
+			message.send(con)?;
+		}
+		TsCommand::MoveChannel { id, password, reply } => {
+			// saved when we arrive, so a failing move isn't retried on every start
+			match move_client(con, tsclientlib::ChannelId(id), password.as_deref()) {
+				Ok(()) => *pending_move = Some(PendingMove { choice: TsChannelChoice { id, password }, reply, started: std::time::Instant::now() }),
+				Err(e) => { let _ = reply.send(Err(e)); },
+			}
+		}
+		TsCommand::Away(message) => {
+			con.get_state()?.client_update().set_away(message.as_deref()).send(con)?;
+		}
+		TsCommand::Follow { name, reply } => {
+			let result = match name {
+				None => {
+					*follow = None;
+					state.lock().expect("Can't lock state!").update(|s| s.ts_follow = None)?;
+					Ok("Stopped following in teamspeak".to_string())
+				},
+				Some(name) => {
+					let ts_state = con.get_state()?;
+					match ts_state.clients.values().find(|c| c.id != ts_state.own_client && c.name.eq_ignore_ascii_case(&name)) {
+						Some(client) => {
+							*follow = Some(client.id);
+							let uid = client_uid(client);
+							state.lock().expect("Can't lock state!").update(|s| s.ts_follow = Some(uid))?;
+							Ok(format!("Following {} in teamspeak", client.name))
+						},
+						None => Err(anyhow::anyhow!("No teamspeak client named {}", name)),
+					}
+				},
+			};
+			let _ = reply.send(result);
+		}
+	}
+	Ok(())
+}
+
+/// Show the discord speakers of this frame on our client, if the rate limit allows it.
+fn show_speakers(con: &mut Connection, display: &mut SpeakerDisplay, speakers: &SpeakerHandle, mixed: &[u32]) -> Result<()> {
+	{
+		let speakers = speakers.lock().expect("Can't lock speakers!");
+		for ssrc in mixed {
+			if let Some(speaker) = speakers.get(ssrc) {
+				display.heard(*ssrc, &speaker.name);
+			}
+		}
+	}
+	let text = match display.update() {
+		Some(v) => v,
+		None => return Ok(()),
+	};
+	let state = con.get_state()?;
+	match display.mode() {
+		DisplayMode::Nickname => state.client_update().set_name(&text).send(con)?,
+		DisplayMode::Description => {
+			let own_client = match state.clients.get(&state.own_client) {
+				Some(v) => v,
+				None => bail!("Own client not found"),
+			};
+			own_client.edit().set_description(&text).send(con)?
+		},
+	}
+	Ok(())
+}
+
+/// Unique ID of a teamspeak client in its usual base64 form
+fn client_uid(client: &tsclientlib::data::Client) -> String {
+	client.uid.as_ref().map(|uid| base64::encode(&uid.0)).unwrap_or_default()
+}
+
+/// Discord follow targets saved by `/follow`
+fn follow_targets(state: &state::BridgeState) -> HashMap<GuildId, UserId> {
+	state.discord_follow.iter()
+		.filter_map(|(guild, user)| Some((GuildId(guild.parse().ok()?), UserId(*user))))
+		.collect()
+}
+
+/// Move our client to another channel
+fn move_client(con: &mut Connection, channel: tsclientlib::ChannelId, password: Option<&str>) -> Result<()> {
+	let state = con.get_state()?;
+	let own_client = match state.clients.get(&state.own_client) {
+		Some(v) => v,
+		None => bail!("Own client not found"),
+	};
+	let mut packet = own_client.client_move(channel);
+	if let Some(password) = password {
+		packet = packet.set_password(password);
+	}
+	packet.send(con)?;
+	Ok(())
+}
+
+/// Names of the channel and all its parents, joined by `/`
+fn channel_path(state: &tsclientlib::data::Connection, mut id: tsclientlib::ChannelId) -> String {
+	let mut names = Vec::new();
+	while let Some(channel) = state.channels.get(&id) {
+		names.push(channel.name.as_str());
+		id = channel.parent;
+	}
+	names.reverse();
+	names.join("/")
+}
+
+/// Chat commands understood by the bridge in teamspeak, without the `!`
+const TS_CHAT_COMMANDS: &[&str] = &["help", "who", "status", "mute", "unmute", "volume", "pause", "resume"];
+const MAX_VOLUME_PERCENT: u32 = 200;
+
+/// A chat message starting with `!` received in teamspeak
+struct TsChatMessage {
+	/// Where to send the answer
+	reply: MessageTarget,
+	invoker: ClientId,
+	text: String,
+}
+
+/// Answer a chat command sent to our teamspeak client.
+fn handle_ts_chat(con: &mut Connection, pipeline: &mut DiscordToTsPipeline, roster: &RosterHandle, permissions: &permissions::TsPermissionConfig, message: TsChatMessage) -> Result<()> {
+	let mut args = message.text.split_whitespace();
+	let command = match args.next().and_then(|c| c.strip_prefix('!')) {
+		Some(v) => v.to_lowercase(),
+		None => return Ok(()),
+	};
+	if !TS_CHAT_COMMANDS.contains(&command.as_str()) {
+		return Ok(());
+	}
+	let state = con.get_state()?;
+	if message.invoker == state.own_client {
+		return Ok(());
+	}
+	let client = match state.clients.get(&message.invoker) {
+		Some(v) => v,
+		None => bail!("Unknown client {}", message.invoker.0),
+	};
+	let server_groups: Vec<u64> = client.server_groups.iter().map(|g| g.0).collect();
+	if !permissions.is_allowed(&command, &server_groups, client.channel_group.0) {
+		state.send_message(message.reply, "You are not allowed to use this command.").send(con)?;
+		return Ok(());
+	}
+
+	let mut update = None;
+	let reply = match command.as_str() {
+		"help" => format!("Commands: {}", TS_CHAT_COMMANDS.iter().map(|c| format!("!{}", c)).collect::<Vec<_>>().join(", ")),
+		"who" => roster.lock().expect("Can't lock roster!").format_discord(),
+		"status" => {
+			let status = pipeline.status.lock().expect("Can't lock status!");
+			let paused = status.paused;
+			format!("Up for {}. Discord to teamspeak: {} speakers, {:.1}% loss{}. Teamspeak to discord: {} speakers, {:.1}% loss{}.",
+				format_duration(status.started.elapsed()),
+				status.discord_to_ts.active_speakers, status.discord_to_ts.loss_percent(),
+				if paused.discord_to_ts { ", paused" } else if pipeline.muted { ", muted" } else { "" },
+				status.ts_to_discord.active_speakers, status.ts_to_discord.loss_percent(),
+				if paused.ts_to_discord { ", paused" } else { "" })
+		},
+		"mute" | "unmute" => {
+			let mute = command == "mute";
+			pipeline.muted = mute;
+			update = Some(state.client_update().set_input_muted(mute));
+			if mute { "Muted discord" } else { "Unmuted discord" }.to_string()
+		},
+		"volume" => match args.next() {
+			None => format!("Discord volume is {:.0}%", pipeline.volume * 100.0),
+			Some(arg) => match arg.trim_end_matches('%').parse::<u32>() {
+				Ok(percent) if percent <= MAX_VOLUME_PERCENT => {
+					pipeline.volume = percent as f32 / 100.0;
+					format!("Set discord volume to {}%", percent)
+				},
+				_ => format!("Expected a volume between 0 and {}%", MAX_VOLUME_PERCENT),
+			},
+		},
+		"pause" | "resume" => {
+			let pause = command == "pause";
+			let name = args.next().unwrap_or("both");
+			let minutes = match args.next().map(str::parse::<u64>) {
+				Some(Ok(v)) if pause && (1..=MAX_PAUSE_MINUTES).contains(&v) => Ok(Some(v)),
+				Some(_) if pause => Err(format!("Expected between 1 and {} minutes until resuming", MAX_PAUSE_MINUTES)),
+				Some(_) => Err("Resume takes no minutes".to_string()),
+				None => Ok(None),
+			};
+			match (name.parse::<Direction>(), minutes) {
+				(Err(_), _) => "Expected a direction: discord_to_ts, ts_to_discord or both".to_string(),
+				(_, Err(e)) => e,
+				(Ok(direction), Ok(minutes)) => {
+					pipeline.status.lock().expect("Can't lock status!").paused.set(direction, pause, minutes.map(|m| Duration::from_secs(m * 60)));
+					match minutes {
+						Some(m) => format!("Paused {} for {} minutes", name.replace('_', " "), m),
+						None => format!("{} {}", if pause { "Paused" } else { "Resumed" }, name.replace('_', " ")),
+					}
+				},
+			}
+		},
+		_ => return Ok(()),
+	};
+	let reply = state.send_message(message.reply, &reply);
+	if let Some(update) = update {
+		update.send(con)?;
+	}
+	reply.send(con)?;
+	Ok(())
+}
+
+/// Processing state of the discord to teamspeak direction
+struct DiscordToTsPipeline {
+	dynamics: dynamics::Dynamics,
+	preprocessor: Preprocessor<u32>,
+	/// Whether the last frame sent to teamspeak contained audio
+	talking: bool,
+	/// Muted by a teamspeak chat command
+	muted: bool,
+	/// Volume of the mix, set by a teamspeak chat command
+	volume: f32,
+	status: StatusHandle,
+	speakers: SpeakerHandle,
+	puppets: puppet::TsPuppets,
+	/// Speakers in the mix of the last frame
+	mixed: Vec<u32>,
+}
+
 /// Create an audio frame for consumption by teamspeak.
 /// Merges all streams and converts them to opus
-async fn process_discord_audio(voice_buffer: &AudioBufferDiscord, encoder: &Arc<Mutex<Encoder>>) -> Option<OutPacket> {
+///
+/// Returns nothing while no one is talking, so our talk indicator in teamspeak turns off.
+async fn process_discord_audio(voice_buffer: &AudioBufferDiscord, encoder: &Arc<Mutex<Encoder>>, recorder: &RecorderHandle, replay: &ReplayHandle, soundboard: &SoundboardHandle, pipeline: &mut DiscordToTsPipeline) -> Option<OutPacket> {
 	// let mut buffer_map;
 	// {
 	// 	let mut lock = voice_buffer.lock().await;
@@ -307,12 +1082,91 @@ async fn process_discord_audio(voice_buffer: &AudioBufferDiscord, encoder: &Arc<
 	// }
 
 	let mut data = [0.0; STEREO_20MS];
+	let mut speakers = 0;
+	pipeline.mixed.clear();
+	let playing;
+	let paused = {
+		let mut status = pipeline.status.lock().expect("Can't lock status!");
+		status.paused.expire();
+		status.paused.discord_to_ts
+	};
 	{
 		let mut lock = voice_buffer.lock().await;
-		lock.fill_buffer(&mut data);
+		// drop everything buffered, so nothing old plays after resuming
+		if paused {
+			lock.reset();
+		}
+		let recorder = recorder.lock().expect("Can't lock recorder!");
+		let known_speakers = pipeline.speakers.lock().expect("Can't lock speakers!");
+		let preprocessor = &mut pipeline.preprocessor;
+		let puppets = &mut pipeline.puppets;
+		let mixed = &mut pipeline.mixed;
+		let muted = pipeline.muted;
+		let mix_volume = pipeline.volume;
+		lock.fill_buffer_with_proc(&mut data, |id, volume, samples| {
+			// gated speakers are silent and don't count as talking
+			let active = preprocessor.process(id, samples);
+			if active {
+				speakers += 1;
+				recorder.push(Track::Discord(*id), Source::Discord, samples)
+			}
+			if puppets.enabled() {
+				let puppet = match known_speakers.get(id) {
+					Some(speaker) if active && !muted => puppets.ensure(*id, &speaker.name),
+					_ => puppets.has_puppet(*id),
+				};
+				if puppet {
+					// muted puppets end their stream when finishing the frame
+					if active && !muted {
+						// volumes of the mix and the speaker, clipped in place of the limiter
+						let gain = volume * mix_volume;
+						samples.iter_mut().for_each(|s| *s = (*s * gain).clamp(-1.0, 1.0));
+						puppets.send(*id, samples);
+					}
+					// not part of our own mix
+					samples.fill(0.0);
+					return;
+				}
+			}
+			if active {
+				mixed.push(*id);
+			}
+		});
+		puppets.finish_frame(|ssrc| known_speakers.contains_key(&ssrc));
+		playing = soundboard.lock().expect("Can't lock soundboard!").mix_next(&mut data);
+		recorder.push(Track::Mix, Source::Discord, &data);
+
+		let (received, lost) = lock.packet_stats();
+		let mut status = pipeline.status.lock().expect("Can't lock status!");
+		let stats = &mut status.discord_to_ts;
+		stats.active_speakers = speakers;
+		stats.buffered_ms = lock.avg_buffered_samples().map(|s| (s / 2) as f32 / (SAMPLE_RATE / 1000) as f32);
+		stats.received_packets = received;
+		stats.lost_packets = lost;
+	}
+	let active = (!pipeline.mixed.is_empty() || playing) && !paused && !pipeline.muted;
+	if !active {
+		pipeline.status.lock().expect("Can't lock status!").discord_to_ts.gain_reduction_db = 0.0;
+	}
+	let preprocessing = pipeline.preprocessor.finish_frame();
+	replay.lock().expect("Can't lock replay buffer!").push(Source::Discord, &data);
+	if !active {
+		if pipeline.talking {
+			pipeline.talking = false;
+			// empty packet, signals the end of our voice stream
+			return Some(OutAudio::new(&AudioData::C2S { id: 0, codec: CodecType::OpusMusic, data: &[] }));
+		}
+		return None;
+	}
+	pipeline.talking = true;
+	if pipeline.volume != 1.0 {
+		data.iter_mut().for_each(|s| *s *= pipeline.volume);
 	}
+	pipeline.dynamics.process(&mut data);
+	pipeline.status.lock().expect("Can't lock status!").discord_to_ts.gain_reduction_db = pipeline.dynamics.reduction_db();
 	let mut encoded = [0; MAX_OPUS_FRAME_SIZE];
 	let encoder_c = encoder.clone();
+	let status = pipeline.status.clone();
 	// don't block the async runtime
 	let res = task::spawn_blocking(move || {
 		let start = std::time::Instant::now();		
@@ -326,9 +1180,9 @@ async fn process_discord_audio(voice_buffer: &AudioBufferDiscord, encoder: &Arc<
 		//println!("Data size: {}/{} enc-length: {}",data.len(),STEREO_20MS,length);
 		//println!("length size: {}",length);
 		// warn on high encoding times
-		let duration = start.elapsed().as_millis();
+		let duration = (start.elapsed() + preprocessing).as_millis();
 		if duration > 2 {
-			eprintln!("Took too {}ms for processing audio!",duration);
+			status.lock().expect("Can't lock status!").warn(format!("Took {}ms for processing audio! ({}ms preprocessing)",duration,preprocessing.as_millis()));
 		}
 		// package into teamspeak audio structure
 		Some(OutAudio::new(&AudioData::C2S { id: 0, codec: CodecType::OpusMusic, data: &encoded[..length] }))
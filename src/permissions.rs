@@ -0,0 +1,152 @@
+//! Command permissions
+//!
+//! Maps bridge commands to the discord roles or permissions required to run them.
+//! Members with the admin role or the administrator permission can run every command.
+//! Teamspeak chat commands are mapped to server and channel groups the same way.
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use serenity::model::id::RoleId;
+use serenity::model::permissions::Permissions;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct PermissionConfig {
+	/// Role allowed to run every command, commands without a rule require it
+	pub admin_role: Option<u64>,
+	/// Rules per command name, used for slash and prefix commands.
+	/// `"<command> <subcommand>"` rules take precedence over the command rule.
+	pub commands: HashMap<String, CommandRule>,
+	/// Teamspeak chat commands
+	pub teamspeak: TsPermissionConfig,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct CommandRule {
+	/// Anyone can run the command
+	pub everyone: bool,
+	/// Any of these roles is required
+	pub roles: Vec<u64>,
+	/// Or any of these permissions, for example "mute_members"
+	pub permissions: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TsPermissionConfig {
+	/// Server groups allowed to run every command, commands without a rule require one of them
+	pub admin_server_groups: Vec<u64>,
+	/// Channel groups allowed to run every command
+	pub admin_channel_groups: Vec<u64>,
+	/// Rules per chat command name, without the `!`
+	pub commands: HashMap<String, TsCommandRule>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TsCommandRule {
+	/// Anyone can run the command
+	pub everyone: bool,
+	/// Any of these server groups is required
+	pub server_groups: Vec<u64>,
+	/// Or any of these channel groups
+	pub channel_groups: Vec<u64>,
+}
+
+struct Rule {
+	everyone: bool,
+	roles: Vec<RoleId>,
+	permissions: Permissions,
+}
+
+pub struct CommandPermissions {
+	admin_role: Option<RoleId>,
+	rules: HashMap<String, Rule>,
+}
+
+impl CommandPermissions {
+	pub fn new(config: &PermissionConfig) -> Result<Self> {
+		let mut rules = HashMap::new();
+		for (command, rule) in &config.commands {
+			let mut permissions = Permissions::empty();
+			for name in &rule.permissions {
+				permissions |= parse_permission(name)?;
+			}
+			rules.insert(command.clone(), Rule {
+				everyone: rule.everyone,
+				roles: rule.roles.iter().map(|r| RoleId(*r)).collect(),
+				permissions,
+			});
+		}
+		Ok(Self { admin_role: config.admin_role.map(RoleId), rules })
+	}
+
+	/// Whether a member with these roles and permissions may run `command` with `subcommand`.
+	///
+	/// Without an admin role and a rule for the command everyone is allowed.
+	pub fn is_allowed(&self, command: &str, subcommand: Option<&str>, roles: &[RoleId], permissions: Permissions) -> bool {
+		if permissions.administrator() {
+			return true;
+		}
+		if let Some(admin) = self.admin_role {
+			if roles.contains(&admin) {
+				return true;
+			}
+		}
+		let rule = subcommand
+			.and_then(|s| self.rules.get(&format!("{} {}", command, s)))
+			.or_else(|| self.rules.get(command));
+		match rule {
+			Some(rule) => {
+				rule.everyone
+					|| rule.roles.iter().any(|r| roles.contains(r))
+					|| permissions.intersects(rule.permissions)
+			},
+			None => self.admin_role.is_none(),
+		}
+	}
+}
+
+impl TsPermissionConfig {
+	/// Whether a client in these groups may run the chat `command`.
+	///
+	/// Without admin groups and a rule for the command everyone is allowed.
+	pub fn is_allowed(&self, command: &str, server_groups: &[u64], channel_group: u64) -> bool {
+		if server_groups.iter().any(|g| self.admin_server_groups.contains(g))
+			|| self.admin_channel_groups.contains(&channel_group) {
+			return true;
+		}
+		match self.commands.get(command) {
+			Some(rule) => {
+				rule.everyone
+					|| server_groups.iter().any(|g| rule.server_groups.contains(g))
+					|| rule.channel_groups.contains(&channel_group)
+			},
+			None => self.admin_server_groups.is_empty() && self.admin_channel_groups.is_empty(),
+		}
+	}
+}
+
+fn parse_permission(name: &str) -> Result<Permissions> {
+	Ok(match name.to_lowercase().as_str() {
+		"administrator" => Permissions::ADMINISTRATOR,
+		"manage_guild" => Permissions::MANAGE_GUILD,
+		"manage_channels" => Permissions::MANAGE_CHANNELS,
+		"manage_roles" => Permissions::MANAGE_ROLES,
+		"manage_messages" => Permissions::MANAGE_MESSAGES,
+		"kick_members" => Permissions::KICK_MEMBERS,
+		"ban_members" => Permissions::BAN_MEMBERS,
+		"moderate_members" => Permissions::MODERATE_MEMBERS,
+		"mute_members" => Permissions::MUTE_MEMBERS,
+		"deafen_members" => Permissions::DEAFEN_MEMBERS,
+		"move_members" => Permissions::MOVE_MEMBERS,
+		"priority_speaker" => Permissions::PRIORITY_SPEAKER,
+		"connect" => Permissions::CONNECT,
+		"speak" => Permissions::SPEAK,
+		"send_messages" => Permissions::SEND_MESSAGES,
+		_ => bail!("Unknown permission {}", name),
+	})
+}
@@ -0,0 +1,95 @@
+//! Per speaker processing
+//!
+//! Runs on the decoded audio of every speaker before it is mixed:
+//! noise suppression, the voice activity gate and loudness normalization.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use crate::denoise::{NoiseSuppressionConfig, NoiseSuppressor};
+use crate::gate::{GateConfig, VoiceGate};
+use crate::loudness::{LoudnessConfig, LoudnessNormalizer};
+use crate::FRAME_SIZE_MS;
+
+/// Forget speakers that didn't talk for 10 minutes (in frames).
+const MAX_IDLE_FRAMES: u64 = 10 * 60 * 1000 / FRAME_SIZE_MS as u64;
+
+struct Speaker {
+	denoise: Option<NoiseSuppressor>,
+	gate: Option<VoiceGate>,
+	/// Frame counter of the last processed frame
+	last_seen: u64,
+}
+
+/// Processing chain for all speakers of one direction.
+pub struct Preprocessor<Id> {
+	denoise: Option<NoiseSuppressionConfig>,
+	gate: Option<GateConfig>,
+	loudness: Option<LoudnessNormalizer<Id>>,
+	speakers: HashMap<Id, Speaker>,
+	frame: u64,
+	/// Time spent in the current frame
+	elapsed: Duration,
+}
+
+impl<Id: Clone + Eq + Hash> Preprocessor<Id> {
+	pub fn new(
+		denoise: Option<NoiseSuppressionConfig>, gate: Option<GateConfig>, loudness: Option<LoudnessConfig>,
+	) -> Self {
+		Self {
+			denoise,
+			gate,
+			loudness: loudness.map(LoudnessNormalizer::new),
+			speakers: Default::default(),
+			frame: 0,
+			elapsed: Duration::ZERO,
+		}
+	}
+
+	/// Process the next frame of a speaker in place, interleaved stereo.
+	///
+	/// Returns `false` if the speaker is not active, the samples are silent then.
+	pub fn process(&mut self, id: &Id, samples: &mut [f32]) -> bool {
+		if samples.is_empty() {
+			return false;
+		}
+		if self.denoise.is_none() && self.gate.is_none() && self.loudness.is_none() {
+			return true;
+		}
+		let start = Instant::now();
+		let (denoise, gate) = (&self.denoise, &self.gate);
+		let speaker = self.speakers.entry(id.clone()).or_insert_with(|| Speaker {
+			denoise: denoise.as_ref().map(NoiseSuppressor::new),
+			gate: gate.as_ref().map(VoiceGate::new),
+			last_seen: 0,
+		});
+		speaker.last_seen = self.frame;
+		if let Some(denoise) = speaker.denoise.as_mut() {
+			denoise.process(samples);
+		}
+		let active = speaker.gate.as_mut().map(|g| g.process(samples)).unwrap_or(true);
+		if !active {
+			self.elapsed += start.elapsed();
+			return false;
+		}
+		if let Some(loudness) = self.loudness.as_mut() {
+			loudness.measure(id, samples);
+			let gain = loudness.gain(id);
+			samples.iter_mut().for_each(|s| *s *= gain);
+		}
+		self.elapsed += start.elapsed();
+		true
+	}
+
+	/// Call once per mixed frame, returns the time spent processing it.
+	pub fn finish_frame(&mut self) -> Duration {
+		self.frame += 1;
+		let frame = self.frame;
+		self.speakers.retain(|_, s| frame - s.last_seen < MAX_IDLE_FRAMES);
+		if let Some(loudness) = self.loudness.as_mut() {
+			loudness.tick();
+		}
+		std::mem::take(&mut self.elapsed)
+	}
+}
@@ -0,0 +1,96 @@
+//! Discord presence
+//!
+//! Mirrors the teamspeak side into the activity of the bot, like `3 on TeamSpeak`, and
+//! optionally into the topic of a discord channel. Discord allows only two topic changes
+//! per channel in ten minutes, so the topic lists everyone who talked since its last update.
+
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+/// Time between two activity changes at least
+const ACTIVITY_INTERVAL: Duration = Duration::from_secs(15);
+/// Longest topic discord accepts
+const MAX_TOPIC_LEN: usize = 1024;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct PresenceConfig {
+	/// Show the amount of teamspeak members as activity of the bot
+	pub activity: bool,
+	/// Channel whose topic shows the teamspeak channel and speakers, disabled if unset
+	pub topic_channel: Option<u64>,
+	/// Minutes between two topic updates at least
+	pub topic_interval_minutes: u64,
+}
+
+impl Default for PresenceConfig {
+	fn default() -> Self { Self { activity: true, topic_channel: None, topic_interval_minutes: 5 } }
+}
+
+pub struct Presence {
+	config: PresenceConfig,
+	/// Teamspeak speakers since the last topic update
+	speakers: BTreeSet<String>,
+	activity: Option<String>,
+	activity_updated: Option<Instant>,
+	topic: Option<String>,
+	topic_updated: Option<Instant>,
+}
+
+impl Presence {
+	pub fn new(config: PresenceConfig) -> Self {
+		Self {
+			config,
+			speakers: BTreeSet::new(),
+			activity: None,
+			activity_updated: None,
+			topic: None,
+			topic_updated: None,
+		}
+	}
+
+	/// Remember the names of talking teamspeak clients for the topic
+	pub fn heard(&mut self, names: impl IntoIterator<Item = String>) { self.speakers.extend(names); }
+
+	/// The new activity text, if it changed and the last change is long enough ago
+	pub fn activity(&mut self, members: usize, recording: bool) -> Option<String> {
+		if !self.config.activity || self.activity_updated.map(|t| t.elapsed() < ACTIVITY_INTERVAL).unwrap_or_default() {
+			return None;
+		}
+		let text = if recording {
+			format!("🔴 Recording · {} on TeamSpeak", members)
+		} else {
+			format!("{} on TeamSpeak", members)
+		};
+		if self.activity.as_ref() == Some(&text) {
+			return None;
+		}
+		self.activity = Some(text.clone());
+		self.activity_updated = Some(Instant::now());
+		Some(text)
+	}
+
+	/// The new topic, if it changed and discord allows another update
+	pub fn topic(&mut self, channel: &str, members: usize) -> Option<String> {
+		self.config.topic_channel?;
+		let interval = Duration::from_secs(self.config.topic_interval_minutes * 60);
+		if self.topic_updated.map(|t| t.elapsed() < interval).unwrap_or_default() {
+			return None;
+		}
+		let mut text = format!("{} on TeamSpeak in {}", members, channel);
+		if !self.speakers.is_empty() {
+			let names: Vec<&str> = self.speakers.iter().map(String::as_str).collect();
+			text.push_str(&format!(", talking: {}", names.join(", ")));
+		}
+		let text: String = text.chars().take(MAX_TOPIC_LEN).collect();
+		self.speakers.clear();
+		if self.topic.as_ref() == Some(&text) {
+			return None;
+		}
+		self.topic = Some(text.clone());
+		self.topic_updated = Some(Instant::now());
+		Some(text)
+	}
+}
@@ -0,0 +1,212 @@
+//! Teamspeak puppets
+//!
+//! Opens an extra teamspeak connection for every active discord speaker, named after the
+//! speaker, so teamspeak users can see who talks and mute single discord speakers.
+//! Speakers beyond the configured cap stay in the mix of the bridge client.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use audiopus::coder::Encoder;
+use futures::prelude::*;
+use serde::Deserialize;
+use slog::{info, o, warn, Logger};
+use tokio::sync::mpsc;
+use tsclientlib::{ChannelId, Connection, DisconnectOptions, Identity, StreamItem};
+use tsproto_packets::packets::{AudioData, CodecType, OutAudio};
+
+use crate::{MAX_OPUS_FRAME_SIZE, STEREO_20MS};
+
+/// Longest nickname teamspeak accepts
+const MAX_NAME_LEN: usize = 30;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct PuppetConfig {
+	/// Maximum amount of teamspeak puppets, 0 disables them
+	pub teamspeak_max: usize,
+	/// Put in front of the discord name of every puppet
+	pub teamspeak_prefix: String,
+	/// Minutes without audio until a puppet disconnects
+	pub idle_minutes: u64,
+	/// Tokens of extra discord bots, one per teamspeak speaker
+	pub discord_tokens: Vec<String>,
+}
+
+impl Default for PuppetConfig {
+	fn default() -> Self {
+		Self { teamspeak_max: 0, teamspeak_prefix: "[D] ".to_string(), idle_minutes: 10, discord_tokens: Vec::new() }
+	}
+}
+
+enum PuppetCommand {
+	/// A 20ms stereo frame
+	Audio(Vec<f32>),
+	/// The speaker stopped talking
+	EndOfStream,
+	Move(ChannelId, Option<String>),
+}
+
+struct Puppet {
+	commands: mpsc::UnboundedSender<PuppetCommand>,
+	/// Unique ID of the puppet identity, to recognize it in the channel
+	uid: String,
+	/// Whether the puppet has an open voice stream
+	talking: bool,
+	/// Whether audio was sent in the current frame
+	sent: bool,
+	last_active: Instant,
+}
+
+/// Puppets of discord speakers by SSRC
+pub struct TsPuppets {
+	logger: Logger,
+	config: PuppetConfig,
+	server: String,
+	server_password: Option<String>,
+	/// Channel of the bridge client, puppets join it
+	channel: Option<(ChannelId, Option<String>)>,
+	puppets: HashMap<u32, Puppet>,
+}
+
+impl TsPuppets {
+	pub fn new(logger: Logger, config: PuppetConfig, server: String, server_password: Option<String>) -> Self {
+		Self { logger, config, server, server_password, channel: None, puppets: HashMap::new() }
+	}
+
+	pub fn enabled(&self) -> bool { self.config.teamspeak_max > 0 }
+
+	/// Whether the audio of this speaker goes through its own puppet
+	pub fn has_puppet(&self, ssrc: u32) -> bool { self.puppets.contains_key(&ssrc) }
+
+	/// Whether the teamspeak client with this unique ID is one of our puppets
+	pub fn is_puppet(&self, uid: &str) -> bool { self.puppets.values().any(|p| p.uid == uid) }
+
+	/// Create a puppet for this speaker if there is room, returns whether it has one.
+	pub fn ensure(&mut self, ssrc: u32, name: &str) -> bool {
+		if self.puppets.contains_key(&ssrc) {
+			return true;
+		}
+		if self.puppets.len() >= self.config.teamspeak_max {
+			return false;
+		}
+		let (channel, channel_password) = match &self.channel {
+			Some(v) => v.clone(),
+			None => return false,
+		};
+		let name: String = format!("{}{}", self.config.teamspeak_prefix, name).chars().take(MAX_NAME_LEN).collect();
+		let identity = Identity::create();
+		let uid = identity.key().to_pub().get_uid();
+		let mut options = Connection::build(self.server.clone())
+			.name(name.clone())
+			.identity(identity)
+			.channel_id(channel)
+			.log_commands(false)
+			.log_packets(false)
+			.log_udp_packets(false);
+		if let Some(password) = &self.server_password {
+			options = options.password(password.clone());
+		}
+		if let Some(password) = channel_password {
+			options = options.channel_password(password);
+		}
+		let con = match options.connect() {
+			Ok(v) => v,
+			Err(e) => {
+				warn!(self.logger, "Failed to connect puppet"; "name" => &name, "error" => %e);
+				return false;
+			}
+		};
+		let (sender, receiver) = mpsc::unbounded_channel();
+		let logger = self.logger.new(o!("puppet" => name));
+		tokio::spawn(async move {
+			match run_puppet(&logger, con, receiver).await {
+				Ok(()) => info!(logger, "Puppet disconnected"),
+				Err(e) => warn!(logger, "Puppet failed"; "error" => %e),
+			}
+		});
+		self.puppets.insert(ssrc, Puppet { commands: sender, uid, talking: false, sent: false, last_active: Instant::now() });
+		true
+	}
+
+	/// Send a frame of this speaker through its puppet
+	pub fn send(&mut self, ssrc: u32, samples: &[f32]) {
+		if let Some(puppet) = self.puppets.get_mut(&ssrc) {
+			puppet.sent = true;
+			puppet.talking = true;
+			puppet.last_active = Instant::now();
+			// the end of a stream can be shorter, opus only takes whole frames
+			let mut frame = samples.to_vec();
+			frame.resize(STEREO_20MS, 0.0);
+			let _ = puppet.commands.send(PuppetCommand::Audio(frame));
+		}
+	}
+
+	/// End the streams of puppets without audio in this frame and remove those of speakers
+	/// which left or were idle for too long.
+	pub fn finish_frame(&mut self, present: impl Fn(u32) -> bool) {
+		let idle = Duration::from_secs(self.config.idle_minutes * 60);
+		self.puppets.retain(|ssrc, puppet| {
+			if !puppet.sent && puppet.talking {
+				puppet.talking = false;
+				let _ = puppet.commands.send(PuppetCommand::EndOfStream);
+			}
+			puppet.sent = false;
+			// dropping the sender disconnects the puppet
+			present(*ssrc) && puppet.last_active.elapsed() < idle && !puppet.commands.is_closed()
+		});
+	}
+
+	/// Move all puppets along with the bridge client
+	pub fn set_channel(&mut self, channel: ChannelId, password: Option<String>) {
+		if self.channel.as_ref().map(|(c, _)| *c) == Some(channel) {
+			return;
+		}
+		for puppet in self.puppets.values() {
+			let _ = puppet.commands.send(PuppetCommand::Move(channel, password.clone()));
+		}
+		self.channel = Some((channel, password));
+	}
+}
+
+async fn run_puppet(logger: &Logger, mut con: Connection, mut commands: mpsc::UnboundedReceiver<PuppetCommand>) -> Result<()> {
+	// wait until connected, audio sent before is dropped
+	let r = con
+		.events()
+		.try_filter(|e| future::ready(matches!(e, StreamItem::BookEvents(_))))
+		.next()
+		.await;
+	if let Some(r) = r {
+		r?;
+	}
+	while commands.try_recv().is_ok() {}
+
+	let encoder = Encoder::new(audiopus::SampleRate::Hz48000, audiopus::Channels::Stereo, audiopus::Application::Voip)?;
+	let mut encoded = [0; MAX_OPUS_FRAME_SIZE];
+	loop {
+		let events = con.events().try_for_each(|_| future::ok(()));
+		tokio::select! {
+			command = commands.recv() => match command {
+				Some(PuppetCommand::Audio(samples)) => {
+					match encoder.encode_float(&samples, &mut encoded) {
+						Ok(length) => con.send_audio(OutAudio::new(&AudioData::C2S { id: 0, codec: CodecType::OpusMusic, data: &encoded[..length] }))?,
+						Err(e) => warn!(logger, "Failed to encode puppet audio"; "error" => %e),
+					}
+				},
+				Some(PuppetCommand::EndOfStream) => {
+					con.send_audio(OutAudio::new(&AudioData::C2S { id: 0, codec: CodecType::OpusMusic, data: &[] }))?;
+				},
+				Some(PuppetCommand::Move(channel, password)) => crate::move_client(&mut con, channel, password.as_deref())?,
+				None => break,
+			},
+			r = events => {
+				r?;
+				bail!("Disconnected");
+			}
+		}
+	}
+	con.disconnect(DisconnectOptions::new())?;
+	con.events().for_each(|_| future::ready(())).await;
+	Ok(())
+}
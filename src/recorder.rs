@@ -0,0 +1,379 @@
+//! Session recording
+//!
+//! Writes one Ogg/Opus file per speaker on both sides and one for the mix of both directions.
+//! All tracks of a recording share the same timeline, starting with the recording itself.
+//! Speakers joining later are padded with silence, so the files can be layered in any editor.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use audiopus::coder::Encoder;
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use slog::{debug, info, o, warn, Logger};
+
+use crate::{TsVoiceId, FRAME_SIZE_MS, MAX_OPUS_FRAME_SIZE, SAMPLE_RATE, STEREO_20MS};
+
+/// Samples per channel of one frame, the granule position unit of ogg/opus.
+const FRAME_SAMPLES: u64 = (SAMPLE_RATE * FRAME_SIZE_MS / 1000) as u64;
+/// Amount of frames a source can drift from the wall clock before it is re-aligned.
+const MAX_DRIFT_FRAMES: u64 = 5;
+/// Keep frames open for this long before encoding them, so late input can still be mixed in.
+const MIX_DELAY_FRAMES: u64 = 10;
+/// Finish an ogg page every second, so an aborted recording stays readable.
+const FRAMES_PER_PAGE: u64 = 50;
+
+pub type RecorderHandle = Arc<std::sync::Mutex<Recorder>>;
+
+/// A single output file of a recording.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Track {
+	/// Discord speaker by SSRC
+	Discord(u32),
+	/// Teamspeak speaker
+	Teamspeak(TsVoiceId),
+	/// Both directions of the bridge mixed together
+	Mix,
+}
+
+/// Side of the bridge the audio was taken from.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Source {
+	Discord,
+	Teamspeak,
+}
+
+enum Message {
+	Frame { track: Track, source: Source, frame: u64, samples: Vec<f32> },
+	Label(Track, String),
+}
+
+/// Records both sides of the bridge, if a session is running.
+pub struct Recorder {
+	logger: Logger,
+	path: PathBuf,
+	session: Option<Session>,
+	/// Readable names for tracks, like the discord user of an SSRC.
+	labels: HashMap<Track, String>,
+}
+
+/// A running recording.
+pub struct Session {
+	start: Instant,
+	dir: PathBuf,
+	sender: mpsc::Sender<Message>,
+	worker: JoinHandle<()>,
+}
+
+impl Recorder {
+	pub fn new(logger: Logger, path: PathBuf) -> Self {
+		Self { logger, path, session: None, labels: Default::default() }
+	}
+
+	/// Start a new recording, returns the directory it is written to.
+	pub fn start(&mut self) -> Result<PathBuf> {
+		if self.session.is_some() {
+			bail!("Already recording!");
+		}
+		let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+		let dir = self.path.join(timestamp.to_string());
+		std::fs::create_dir_all(&dir)
+			.with_context(|| format!("Can't create recording directory {}", dir.display()))?;
+
+		let (sender, receiver) = mpsc::channel();
+		let worker = Worker::new(
+			self.logger.new(o!("recording" => timestamp)),
+			dir.clone(),
+			self.labels.clone(),
+			timestamp as u32,
+		)?;
+		let worker = std::thread::Builder::new()
+			.name("recorder".into())
+			.spawn(move || worker.run(receiver))?;
+		info!(self.logger, "Started recording"; "path" => %dir.display());
+		self.session = Some(Session { start: Instant::now(), dir: dir.clone(), sender, worker });
+		Ok(dir)
+	}
+
+	/// Stop the current recording. Call [`Session::finish`] to wait for all files to be written.
+	pub fn stop(&mut self) -> Option<Session> { self.session.take() }
+
+	pub fn is_recording(&self) -> bool { self.session.is_some() }
+
+	/// Set the name of a track, used for its file name.
+	pub fn set_label(&mut self, track: Track, label: String) {
+		if self.labels.get(&track) == Some(&label) {
+			return;
+		}
+		if let Some(session) = &self.session {
+			let _ = session.sender.send(Message::Label(track.clone(), label.clone()));
+		}
+		self.labels.insert(track, label);
+	}
+
+	/// Add audio of a track, the position on the timeline is based on the current time.
+	pub fn push(&self, track: Track, source: Source, samples: &[f32]) {
+		if let Some(session) = &self.session {
+			if samples.is_empty() {
+				return;
+			}
+			let frame = session.start.elapsed().as_millis() as u64 / FRAME_SIZE_MS as u64;
+			let msg = Message::Frame { track, source, frame, samples: samples.to_vec() };
+			if session.sender.send(msg).is_err() {
+				warn!(self.logger, "Recorder stopped unexpectedly");
+			}
+		}
+	}
+}
+
+impl Session {
+	/// Wait for all tracks to be finished, returns the recording directory.
+	///
+	/// Blocks until the writer is done.
+	pub fn finish(self) -> PathBuf {
+		drop(self.sender);
+		if self.worker.join().is_err() {
+			eprintln!("Recording worker panicked!");
+		}
+		self.dir
+	}
+}
+
+/// Encodes and writes all tracks of a session, runs in its own thread.
+struct Worker {
+	logger: Logger,
+	dir: PathBuf,
+	labels: HashMap<Track, String>,
+	tracks: HashMap<Track, TrackWriter>,
+	/// Pre-encoded silence, used for padding tracks.
+	silence: Vec<u8>,
+	next_serial: u32,
+	/// The latest frame seen on any track.
+	newest_frame: u64,
+}
+
+impl Worker {
+	fn new(logger: Logger, dir: PathBuf, labels: HashMap<Track, String>, serial: u32) -> Result<Self> {
+		let encoder = new_encoder()?;
+		let mut encoded = [0; MAX_OPUS_FRAME_SIZE];
+		let len = encoder.encode_float(&[0.0; STEREO_20MS], &mut encoded)?;
+		Ok(Self {
+			logger,
+			dir,
+			labels,
+			tracks: Default::default(),
+			silence: encoded[..len].to_vec(),
+			next_serial: serial,
+			newest_frame: 0,
+		})
+	}
+
+	fn run(mut self, receiver: mpsc::Receiver<Message>) {
+		for msg in receiver {
+			match msg {
+				Message::Label(track, label) => {
+					self.labels.insert(track, label);
+				}
+				Message::Frame { track, source, frame, samples } => {
+					if let Err(e) = self.handle_frame(track, source, frame, &samples) {
+						warn!(self.logger, "Failed to record frame"; "error" => %e);
+					}
+				}
+			}
+		}
+		// align all tracks to the same end
+		for (track, writer) in self.tracks.iter_mut() {
+			if let Err(e) = writer.finish(self.newest_frame, &self.silence) {
+				warn!(self.logger, "Failed to finish track"; "track" => ?track, "error" => %e);
+			}
+		}
+		info!(self.logger, "Finished recording"; "tracks" => self.tracks.len());
+	}
+
+	fn handle_frame(&mut self, track: Track, source: Source, frame: u64, samples: &[f32]) -> Result<()> {
+		self.newest_frame = self.newest_frame.max(frame);
+		if !self.tracks.contains_key(&track) {
+			let path = self.dir.join(self.file_name(&track));
+			debug!(self.logger, "Adding track"; "path" => %path.display());
+			let writer = TrackWriter::new(path, self.next_serial, self.labels.get(&track))?;
+			self.next_serial = self.next_serial.wrapping_add(1);
+			self.tracks.insert(track.clone(), writer);
+		}
+		let writer = self.tracks.get_mut(&track).expect("Track inserted above");
+		if !writer.add(source, frame, samples) {
+			debug!(self.logger, "Dropping late frame"; "track" => ?track, "frame" => frame);
+		}
+
+		let limit = self.newest_frame.saturating_sub(MIX_DELAY_FRAMES);
+		for writer in self.tracks.values_mut() {
+			writer.flush_until(limit, &self.silence)?;
+		}
+		Ok(())
+	}
+
+	fn file_name(&self, track: &Track) -> String {
+		// teamspeak nicknames may contain anything
+		let label = self.labels.get(track).map(|l| {
+			l.chars().map(|c| if c.is_alphanumeric() || c == '-' { c } else { '_' }).collect::<String>()
+		});
+		match (track, label) {
+			(Track::Mix, _) => "mix.opus".to_string(),
+			(Track::Discord(ssrc), Some(label)) => format!("discord_{}_{}.opus", label, ssrc),
+			(Track::Discord(ssrc), None) => format!("discord_{}.opus", ssrc),
+			(Track::Teamspeak((_, client)), Some(label)) => {
+				format!("teamspeak_{}_{}.opus", label, client.0)
+			}
+			(Track::Teamspeak((_, client)), None) => format!("teamspeak_{}.opus", client.0),
+		}
+	}
+}
+
+/// One ogg/opus file
+struct TrackWriter {
+	writer: PacketWriter<'static, BufWriter<File>>,
+	encoder: Encoder,
+	serial: u32,
+	/// Samples per channel the decoder drops at the start, part of every granule position.
+	pre_skip: u64,
+	/// The next frame on the timeline to encode.
+	next_frame: u64,
+	/// Position of the next frame per source, keeps input contiguous despite jitter.
+	positions: HashMap<Source, u64>,
+	/// Frames waiting for encoding, sources are summed up.
+	pending: BTreeMap<u64, Vec<f32>>,
+}
+
+impl TrackWriter {
+	fn new(path: PathBuf, serial: u32, label: Option<&String>) -> Result<Self> {
+		let file = File::create(&path)
+			.with_context(|| format!("Can't create track {}", path.display()))?;
+		let mut writer = PacketWriter::new(BufWriter::new(file));
+		let encoder = new_encoder()?;
+		let pre_skip = encoder.lookahead()? as u16;
+
+		// RFC 7845 identification header
+		let mut head = Vec::with_capacity(19);
+		head.extend_from_slice(b"OpusHead");
+		head.push(1);
+		head.push(2);
+		head.extend_from_slice(&pre_skip.to_le_bytes());
+		head.extend_from_slice(&(SAMPLE_RATE as u32).to_le_bytes());
+		head.extend_from_slice(&0i16.to_le_bytes());
+		head.push(0);
+		writer.write_packet(head, serial, PacketWriteEndInfo::EndPage, 0)?;
+
+		// RFC 7845 comment header
+		let vendor = concat!("voice_bridge ", env!("CARGO_PKG_VERSION"));
+		let mut tags = Vec::new();
+		tags.extend_from_slice(b"OpusTags");
+		tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+		tags.extend_from_slice(vendor.as_bytes());
+		match label {
+			Some(label) => {
+				let title = format!("TITLE={}", label);
+				tags.extend_from_slice(&1u32.to_le_bytes());
+				tags.extend_from_slice(&(title.len() as u32).to_le_bytes());
+				tags.extend_from_slice(title.as_bytes());
+			}
+			None => tags.extend_from_slice(&0u32.to_le_bytes()),
+		}
+		writer.write_packet(tags, serial, PacketWriteEndInfo::EndPage, 0)?;
+
+		Ok(Self {
+			writer,
+			encoder,
+			serial,
+			pre_skip: u64::from(pre_skip),
+			next_frame: 0,
+			positions: Default::default(),
+			pending: Default::default(),
+		})
+	}
+
+	/// Queue samples for the given frame, returns false if it came too late.
+	fn add(&mut self, source: Source, frame: u64, samples: &[f32]) -> bool {
+		let mut position = match self.positions.get(&source) {
+			Some(pos) if pos.abs_diff(frame) <= MAX_DRIFT_FRAMES => *pos,
+			_ => frame,
+		};
+		for chunk in samples.chunks(STEREO_20MS) {
+			if position < self.next_frame {
+				return false;
+			}
+			let pending = self.pending.entry(position).or_insert_with(|| vec![0.0; STEREO_20MS]);
+			for (out, sample) in pending.iter_mut().zip(chunk) {
+				*out += sample;
+			}
+			position += 1;
+		}
+		self.positions.insert(source, position);
+		true
+	}
+
+	/// Encode all frames up to and including `limit`, gaps are filled with silence.
+	fn flush_until(&mut self, limit: u64, silence: &[u8]) -> Result<()> {
+		while self.next_frame <= limit {
+			match self.pending.remove(&self.next_frame) {
+				Some(data) => self.encode(&data, false)?,
+				None => self.write(silence.to_vec(), false)?,
+			}
+		}
+		Ok(())
+	}
+
+	/// Write all remaining frames up to `end` and close the stream.
+	fn finish(&mut self, end: u64, silence: &[u8]) -> Result<()> {
+		let end = self.pending.keys().next_back().copied().unwrap_or(0).max(end);
+		self.flush_until(end, silence)?;
+		self.write(silence.to_vec(), true)?;
+		self.writer.inner_mut().flush()?;
+		Ok(())
+	}
+
+	/// Encode and write one frame of [`STEREO_20MS`] samples.
+	fn encode(&mut self, data: &[f32], last: bool) -> Result<()> {
+		let mut encoded = [0; MAX_OPUS_FRAME_SIZE];
+		let len = self.encoder.encode_float(data, &mut encoded)?;
+		self.write(encoded[..len].to_vec(), last)
+	}
+
+	fn write(&mut self, packet: Vec<u8>, last: bool) -> Result<()> {
+		self.next_frame += 1;
+		let info = if last {
+			PacketWriteEndInfo::EndStream
+		} else if self.next_frame.is_multiple_of(FRAMES_PER_PAGE) {
+			PacketWriteEndInfo::EndPage
+		} else {
+			PacketWriteEndInfo::NormalPacket
+		};
+		self.writer.write_packet(packet, self.serial, info, self.next_frame * FRAME_SAMPLES + self.pre_skip)?;
+		Ok(())
+	}
+}
+
+/// Write interleaved stereo samples into a single ogg/opus file.
+pub fn write_opus_file(path: PathBuf, samples: &[f32], label: Option<&String>) -> Result<()> {
+	let mut writer = TrackWriter::new(path, 0, label)?;
+	let frames = samples.len().div_ceil(STEREO_20MS);
+	// a stream without end is no valid ogg file
+	if frames == 0 {
+		writer.encode(&[0.0; STEREO_20MS], true)?;
+	}
+	for (i, chunk) in samples.chunks(STEREO_20MS).enumerate() {
+		let mut frame = [0.0; STEREO_20MS];
+		frame[..chunk.len()].copy_from_slice(chunk);
+		writer.encode(&frame, i + 1 == frames)?;
+	}
+	writer.writer.inner_mut().flush()?;
+	Ok(())
+}
+
+fn new_encoder() -> Result<Encoder> {
+	Ok(Encoder::new(audiopus::SampleRate::Hz48000, audiopus::Channels::Stereo, audiopus::Application::Audio)?)
+}
+
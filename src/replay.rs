@@ -0,0 +1,82 @@
+//! Instant replay
+//!
+//! Keeps the last seconds of both mixed directions in memory,
+//! so "what just happened" can be saved as a clip on demand.
+
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+
+use crate::recorder::{self, Source};
+use crate::SAMPLE_RATE;
+
+pub type ReplayHandle = Arc<std::sync::Mutex<ReplayBuffer>>;
+
+/// Rolling buffer of interleaved stereo samples per direction.
+pub struct ReplayBuffer {
+	/// Maximum amount of samples to keep per direction.
+	capacity: usize,
+	buffers: HashMap<Source, VecDeque<f32>>,
+	/// Directory for saved clips
+	path: PathBuf,
+}
+
+impl ReplayBuffer {
+	pub fn new(seconds: usize, path: PathBuf) -> Self {
+		Self { capacity: seconds * SAMPLE_RATE * 2, buffers: Default::default(), path }
+	}
+
+	/// Maximum length of a clip
+	pub fn seconds(&self) -> usize { self.capacity / SAMPLE_RATE / 2 }
+
+	/// Append mixed audio of one direction, drops the oldest samples when full.
+	pub fn push(&mut self, source: Source, samples: &[f32]) {
+		if self.capacity == 0 {
+			return;
+		}
+		let capacity = self.capacity;
+		let buffer = self.buffers.entry(source).or_insert_with(|| VecDeque::with_capacity(capacity));
+		let overflow = (buffer.len() + samples.len()).saturating_sub(self.capacity);
+		buffer.drain(..overflow.min(buffer.len()));
+		let skip = samples.len().saturating_sub(self.capacity);
+		buffer.extend(&samples[skip..]);
+	}
+
+	/// Mix the last `seconds` of both directions together.
+	///
+	/// Both directions are aligned at their end, which is "now".
+	pub fn clip(&self, seconds: usize) -> Vec<f32> {
+		let wanted = seconds.saturating_mul(SAMPLE_RATE * 2).min(self.capacity);
+		let len = self.buffers.values().map(|b| b.len().min(wanted)).max().unwrap_or_default();
+		let mut data = vec![0.0; len];
+		for buffer in self.buffers.values() {
+			let take = buffer.len().min(len);
+			let offset = len - take;
+			for (out, sample) in data[offset..].iter_mut().zip(buffer.range(buffer.len() - take..)) {
+				*out += sample;
+			}
+		}
+		data
+	}
+}
+
+/// Save the last `seconds` into a new ogg/opus file inside the clip directory.
+///
+/// Returns the file and the seconds actually clipped. Blocks while encoding.
+pub fn save_clip(replay: &ReplayHandle, seconds: usize) -> Result<(PathBuf, usize)> {
+	let (data, path) = {
+		let lock = replay.lock().expect("Can't lock replay buffer!");
+		(lock.clip(seconds), lock.path.clone())
+	};
+	if data.is_empty() {
+		bail!("Nothing to clip yet!");
+	}
+	std::fs::create_dir_all(&path)?;
+	let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+	let file = path.join(format!("clip_{}.opus", timestamp));
+	recorder::write_opus_file(file.clone(), &data, None)?;
+	Ok((file, data.len() / SAMPLE_RATE / 2))
+}
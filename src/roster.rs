@@ -0,0 +1,128 @@
+//! Cross platform roster
+//!
+//! Who is in the bridged channel on each side, shown by `/ts_who`, the `!who` teamspeak
+//! chat command and the optional pinned roster message. Also keeps the teamspeak channel
+//! tree for `/ts_channel`.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+pub type RosterHandle = Arc<std::sync::Mutex<Roster>>;
+
+/// A client in the teamspeak channel of the bridge
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TsMember {
+	pub id: u16,
+	/// Unique ID, base64 encoded
+	pub uid: String,
+	pub name: String,
+	pub input_muted: bool,
+	pub output_muted: bool,
+	pub away: Option<String>,
+}
+
+/// A teamspeak channel the bridge can move to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TsChannel {
+	pub id: u64,
+	/// Names of all parents and the channel, joined by `/`
+	pub path: String,
+	pub has_password: bool,
+}
+
+#[derive(Default)]
+pub struct Roster {
+	/// Name of the teamspeak channel
+	ts_channel: String,
+	teamspeak: Vec<TsMember>,
+	/// Display names in the discord voice channel of the bridge
+	discord: Vec<String>,
+	/// Teamspeak channel tree, sorted by path
+	ts_channels: Vec<TsChannel>,
+	/// Increased on every change
+	generation: u64,
+}
+
+impl Roster {
+	/// Changes whenever a member joins, leaves or changes its state
+	pub fn generation(&self) -> u64 { self.generation }
+
+	pub fn set_teamspeak(&mut self, channel: String, mut members: Vec<TsMember>) {
+		members.sort_by_key(|m| m.name.to_lowercase());
+		if channel != self.ts_channel || members != self.teamspeak {
+			self.ts_channel = channel;
+			self.teamspeak = members;
+			self.generation += 1;
+		}
+	}
+
+	/// Name of the teamspeak channel of the bridge
+	pub fn ts_channel(&self) -> &str { &self.ts_channel }
+
+	pub fn teamspeak(&self) -> &[TsMember] { &self.teamspeak }
+
+	pub fn set_ts_channels(&mut self, mut channels: Vec<TsChannel>) {
+		channels.sort_by_key(|c| c.path.to_lowercase());
+		self.ts_channels = channels;
+	}
+
+	pub fn ts_channels(&self) -> &[TsChannel] { &self.ts_channels }
+
+	pub fn set_discord(&mut self, mut members: Vec<String>) {
+		members.sort_by_key(|m| m.to_lowercase());
+		if members != self.discord {
+			self.discord = members;
+			self.generation += 1;
+		}
+	}
+
+	/// Teamspeak members as discord markdown, `talking` are the IDs of speaking clients
+	pub fn format_teamspeak(&self, talking: &HashSet<u16>) -> String {
+		if self.teamspeak.is_empty() {
+			return format!("No one in teamspeak channel **{}**", self.ts_channel);
+		}
+		let mut text = format!("Teamspeak channel **{}** ({}):", self.ts_channel, self.teamspeak.len());
+		for member in &self.teamspeak {
+			let mut states = Vec::new();
+			if talking.contains(&member.id) {
+				states.push("talking".to_string());
+			}
+			if member.input_muted {
+				states.push("muted".to_string());
+			}
+			if member.output_muted {
+				states.push("deafened".to_string());
+			}
+			match member.away.as_deref() {
+				Some("") => states.push("away".to_string()),
+				Some(message) => states.push(format!("away: {}", message)),
+				None => (),
+			}
+			text.push_str("\n- ");
+			text.push_str(&member.name);
+			if !states.is_empty() {
+				text.push_str(&format!(" *({})*", states.join(", ")));
+			}
+		}
+		text
+	}
+
+	/// Discord members as plain text for teamspeak
+	pub fn format_discord(&self) -> String {
+		if self.discord.is_empty() {
+			"No one in discord".to_string()
+		} else {
+			format!("In discord ({}): {}", self.discord.len(), self.discord.join(", "))
+		}
+	}
+
+	/// Both sides for the pinned roster message
+	pub fn format_pinned(&self) -> String {
+		let discord = if self.discord.is_empty() {
+			"No one".to_string()
+		} else {
+			self.discord.iter().map(|m| format!("- {}", m)).collect::<Vec<_>>().join("\n")
+		};
+		format!("{}\n\nDiscord voice ({}):\n{}", self.format_teamspeak(&HashSet::new()), self.discord.len(), discord)
+	}
+}
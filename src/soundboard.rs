@@ -0,0 +1,284 @@
+//! Soundboard
+//!
+//! Plays local audio clips into both sides of the bridge.
+//! Frames are generated on the teamspeak tick and handed to discord through a small buffer,
+//! so both sides hear the same audio at the same time.
+//!
+//! Supported are WAV files (PCM 8/16/24/32 bit or float) and Ogg/Opus files.
+
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use audiopus::coder::Decoder;
+use audiopus::{Channels, SampleRate};
+
+use crate::{SAMPLE_RATE, STEREO_20MS};
+
+/// Maximum amount of samples buffered for discord, 200ms.
+const MAX_DISCORD_BUFFER: usize = STEREO_20MS * 10;
+/// Supported file extensions
+const EXTENSIONS: &[&str] = &["wav", "opus", "ogg"];
+
+pub type SoundboardHandle = Arc<std::sync::Mutex<Soundboard>>;
+
+/// A decoded clip, interleaved stereo at 48kHz.
+pub struct Clip {
+	pub name: String,
+	samples: Vec<f32>,
+	pub volume: f32,
+}
+
+struct Playing {
+	clip: Clip,
+	position: usize,
+}
+
+pub struct Soundboard {
+	/// Directory containing the clips
+	path: PathBuf,
+	queue: VecDeque<Clip>,
+	current: Option<Playing>,
+	/// Frames already played into teamspeak, waiting for discord.
+	discord_buffer: VecDeque<f32>,
+}
+
+impl Clip {
+	/// Duration in seconds
+	pub fn duration(&self) -> f32 { self.samples.len() as f32 / (SAMPLE_RATE * 2) as f32 }
+}
+
+impl Soundboard {
+	pub fn new(path: PathBuf) -> Self {
+		Self { path, queue: Default::default(), current: None, discord_buffer: Default::default() }
+	}
+
+	/// Names of all playable clips in the soundboard directory.
+	pub fn list(&self) -> Result<Vec<String>> {
+		let mut names = Vec::new();
+		for entry in std::fs::read_dir(&self.path)
+			.with_context(|| format!("Can't read soundboard directory {}", self.path.display()))?
+		{
+			let path = entry?.path();
+			let supported = path
+				.extension()
+				.and_then(|e| e.to_str())
+				.map(|e| EXTENSIONS.contains(&e.to_lowercase().as_str()))
+				.unwrap_or_default();
+			if supported && path.is_file() {
+				if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+					names.push(name.to_string());
+				}
+			}
+		}
+		names.sort();
+		Ok(names)
+	}
+
+	/// Path of a clip, rejects anything outside of the soundboard directory.
+	pub fn clip_path(&self, name: &str) -> Result<PathBuf> {
+		if name.contains(['/', '\\']) || name.starts_with('.') {
+			bail!("Invalid clip name!");
+		}
+		let path = self.path.join(name);
+		if !path.is_file() {
+			bail!("No clip named {}!", name);
+		}
+		Ok(path)
+	}
+
+	/// Add a clip to the queue, returns its position, 0 if it plays right away.
+	pub fn enqueue(&mut self, clip: Clip) -> usize {
+		self.queue.push_back(clip);
+		self.queue.len() - usize::from(self.current.is_none())
+	}
+
+	/// Stop playback and clear the queue.
+	pub fn stop(&mut self) {
+		self.queue.clear();
+		self.current = None;
+		self.discord_buffer.clear();
+	}
+
+	/// Skip the current clip, returns the name of the skipped clip.
+	pub fn skip(&mut self) -> Option<String> { self.current.take().map(|p| p.clip.name) }
+
+	/// Currently playing clip and the queue
+	pub fn queue(&self) -> (Option<&Clip>, impl Iterator<Item = &Clip>) {
+		(self.current.as_ref().map(|p| &p.clip), self.queue.iter())
+	}
+
+	/// Mix the next frame into the teamspeak bound `buf` and keep it for discord.
+	///
+	/// Returns whether a clip is playing.
+	pub fn mix_next(&mut self, buf: &mut [f32]) -> bool {
+		if self.current.is_none() {
+			self.current = self.queue.pop_front().map(|clip| Playing { clip, position: 0 });
+		}
+		let playing = match self.current.as_mut() {
+			Some(v) => v,
+			None => return false,
+		};
+		let end = (playing.position + buf.len()).min(playing.clip.samples.len());
+		let frame = &playing.clip.samples[playing.position..end];
+		let volume = playing.clip.volume;
+		for (out, sample) in buf.iter_mut().zip(frame) {
+			*out += sample * volume;
+		}
+		self.discord_buffer.extend(frame.iter().map(|s| s * volume));
+		let overflow = self.discord_buffer.len().saturating_sub(MAX_DISCORD_BUFFER);
+		self.discord_buffer.drain(..overflow);
+
+		playing.position = end;
+		if end >= playing.clip.samples.len() {
+			self.current = None;
+		}
+		true
+	}
+
+	/// Mix buffered frames into the discord bound `buf`.
+	pub fn mix_discord(&mut self, buf: &mut [f32]) {
+		let len = buf.len().min(self.discord_buffer.len());
+		for (out, sample) in buf.iter_mut().zip(self.discord_buffer.drain(..len)) {
+			*out += sample;
+		}
+	}
+}
+
+/// Load and decode a clip, blocks while decoding.
+pub fn load_clip(path: &Path, volume: f32) -> Result<Clip> {
+	let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_lowercase();
+	let samples = match extension.as_str() {
+		"wav" => decode_wav(path)?,
+		"opus" | "ogg" => decode_ogg_opus(path)?,
+		_ => bail!("Unsupported file type {}", extension),
+	};
+	let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_string();
+	Ok(Clip { name, samples, volume })
+}
+
+fn decode_wav(path: &Path) -> Result<Vec<f32>> {
+	let mut data = Vec::new();
+	BufReader::new(File::open(path)?).read_to_end(&mut data)?;
+	if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+		bail!("Not a WAV file");
+	}
+	let mut format = None;
+	let mut samples = None;
+	let mut pos = 12;
+	while pos + 8 <= data.len() {
+		let id = &data[pos..pos + 4];
+		let len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into()?) as usize;
+		let chunk = &data[pos + 8..(pos + 8 + len).min(data.len())];
+		match id {
+			b"fmt " if chunk.len() >= 16 => {
+				let tag = u16::from_le_bytes([chunk[0], chunk[1]]);
+				let channels = u16::from_le_bytes([chunk[2], chunk[3]]) as usize;
+				let rate = u32::from_le_bytes(chunk[4..8].try_into()?) as usize;
+				let bits = u16::from_le_bytes([chunk[14], chunk[15]]);
+				// WAVE_FORMAT_EXTENSIBLE stores the real format in the sub format GUID
+				let tag = if tag == 0xFFFE && chunk.len() >= 26 {
+					u16::from_le_bytes([chunk[24], chunk[25]])
+				} else {
+					tag
+				};
+				format = Some((tag, channels, rate, bits));
+			}
+			b"data" => samples = Some(chunk),
+			_ => {}
+		}
+		// chunks are padded to an even size
+		pos += 8 + len + (len & 1);
+	}
+	let (tag, channels, rate, bits) = format.context("Missing WAV format")?;
+	let samples = samples.context("Missing WAV data")?;
+	if channels == 0 || rate == 0 {
+		bail!("Invalid WAV format");
+	}
+	let decoded: Vec<f32> = match (tag, bits) {
+		(1, 8) => samples.iter().map(|s| (*s as f32 - 128.0) / 128.0).collect(),
+		(1, 16) => samples
+			.chunks_exact(2)
+			.map(|s| i16::from_le_bytes([s[0], s[1]]) as f32 / 32768.0)
+			.collect(),
+		(1, 24) => samples
+			.chunks_exact(3)
+			.map(|s| i32::from_le_bytes([0, s[0], s[1], s[2]]) as f32 / 2_147_483_648.0)
+			.collect(),
+		(1, 32) => samples
+			.chunks_exact(4)
+			.map(|s| i32::from_le_bytes([s[0], s[1], s[2], s[3]]) as f32 / 2_147_483_648.0)
+			.collect(),
+		(3, 32) => samples
+			.chunks_exact(4)
+			.map(|s| f32::from_le_bytes([s[0], s[1], s[2], s[3]]))
+			.collect(),
+		_ => bail!("Unsupported WAV format {} with {} bits", tag, bits),
+	};
+	Ok(resample(&to_stereo(&decoded, channels), rate))
+}
+
+fn decode_ogg_opus(path: &Path) -> Result<Vec<f32>> {
+	let mut reader = ogg::reading::PacketReader::new(BufReader::new(File::open(path)?));
+	let head = reader.read_packet()?.context("Empty ogg file")?;
+	if head.data.len() < 19 || &head.data[..8] != b"OpusHead" {
+		bail!("Not an Ogg/Opus file");
+	}
+	let channels = match head.data[9] {
+		1 => Channels::Mono,
+		2 => Channels::Stereo,
+		c => bail!("Unsupported amount of channels: {}", c),
+	};
+	let pre_skip = u16::from_le_bytes([head.data[10], head.data[11]]) as usize * 2;
+	// comment header
+	reader.read_packet()?;
+
+	let mut decoder = Decoder::new(SampleRate::Hz48000, channels)?;
+	let channel_num = channels as usize;
+	// 120ms is the longest possible opus packet
+	let mut buffer = vec![0.0; SAMPLE_RATE / 1000 * 120 * channel_num];
+	let mut decoded = Vec::new();
+	while let Some(packet) = reader.read_packet()? {
+		let len = decoder.decode_float(Some(&packet.data[..]), &mut buffer[..], false)?;
+		decoded.extend_from_slice(&buffer[..len * channel_num]);
+	}
+	let mut samples = to_stereo(&decoded, channel_num);
+	samples.drain(..pre_skip.min(samples.len()));
+	Ok(samples)
+}
+
+/// Convert interleaved samples to stereo, mono is duplicated, additional channels are dropped.
+fn to_stereo(samples: &[f32], channels: usize) -> Vec<f32> {
+	match channels {
+		2 => samples.to_vec(),
+		1 => samples.iter().flat_map(|s| [*s, *s]).collect(),
+		_ => samples.chunks_exact(channels).flat_map(|s| [s[0], s[1]]).collect(),
+	}
+}
+
+/// Linear resampling of interleaved stereo samples to 48kHz.
+fn resample(samples: &[f32], rate: usize) -> Vec<f32> {
+	if rate == SAMPLE_RATE {
+		return samples.to_vec();
+	}
+	let frames = samples.len() / 2;
+	let out_frames = frames * SAMPLE_RATE / rate;
+	let step = rate as f64 / SAMPLE_RATE as f64;
+	let mut out = Vec::with_capacity(out_frames * 2);
+	for i in 0..out_frames {
+		let pos = i as f64 * step;
+		let idx = pos as usize;
+		let frac = (pos - idx as f64) as f32;
+		let next = (idx + 1).min(frames - 1);
+		for channel in 0..2 {
+			let a = samples[idx * 2 + channel];
+			let b = samples[next * 2 + channel];
+			out.push(a + (b - a) * frac);
+		}
+	}
+	out
+}
@@ -0,0 +1,97 @@
+//! Speaker display
+//!
+//! Shows who talks in discord on our teamspeak client, in its description or nickname.
+//! Changes are rate limited, teamspeak kicks clients for flooding.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+/// Longest nickname teamspeak accepts
+const MAX_NAME_LEN: usize = 30;
+/// Speakers are shown for this long after they were heard last
+const HOLD_TIME: Duration = Duration::from_secs(3);
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DisplayMode {
+	Description,
+	Nickname,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct SpeakerDisplayConfig {
+	/// Where to show the speakers, disabled if unset
+	pub mode: Option<DisplayMode>,
+	/// Put in front of the speakers in the nickname
+	pub prefix: String,
+	/// Seconds between two updates at least
+	pub interval_secs: u64,
+}
+
+impl Default for SpeakerDisplayConfig {
+	fn default() -> Self { Self { mode: None, prefix: "[D] ".to_string(), interval_secs: 5 } }
+}
+
+pub struct SpeakerDisplay {
+	mode: DisplayMode,
+	prefix: String,
+	interval: Duration,
+	/// Our nickname while no one talks
+	base_name: String,
+	/// Name and last time heard by SSRC
+	heard: HashMap<u32, (String, Instant)>,
+	shown: String,
+	last_update: Option<Instant>,
+}
+
+impl SpeakerDisplay {
+	/// `None` if disabled, `base_name` is our normal nickname
+	pub fn new(config: &SpeakerDisplayConfig, base_name: String) -> Option<Self> {
+		let mode = config.mode?;
+		let shown = match mode {
+			DisplayMode::Description => String::new(),
+			DisplayMode::Nickname => base_name.clone(),
+		};
+		Some(Self {
+			mode,
+			prefix: config.prefix.clone(),
+			interval: Duration::from_secs(config.interval_secs),
+			base_name,
+			heard: HashMap::new(),
+			shown,
+			last_update: None,
+		})
+	}
+
+	pub fn mode(&self) -> DisplayMode { self.mode }
+
+	/// Mark a speaker as talking in this frame
+	pub fn heard(&mut self, ssrc: u32, name: &str) {
+		self.heard.insert(ssrc, (name.to_string(), Instant::now()));
+	}
+
+	/// The new description or nickname, if it changed and the rate limit allows an update
+	pub fn update(&mut self) -> Option<String> {
+		if self.last_update.map(|t| t.elapsed() < self.interval).unwrap_or_default() {
+			return None;
+		}
+		self.heard.retain(|_, (_, time)| time.elapsed() < HOLD_TIME);
+		let mut names: Vec<&str> = self.heard.values().map(|(name, _)| name.as_str()).collect();
+		names.sort_unstable();
+		let text = match (self.mode, names.is_empty()) {
+			(DisplayMode::Description, true) => String::new(),
+			(DisplayMode::Description, false) => format!("Talking in discord: {}", names.join(", ")),
+			(DisplayMode::Nickname, true) => self.base_name.clone(),
+			(DisplayMode::Nickname, false) => format!("{}{}", self.prefix, names.join(", ")).chars().take(MAX_NAME_LEN).collect(),
+		};
+		if text == self.shown {
+			return None;
+		}
+		self.shown = text.clone();
+		self.last_update = Some(Instant::now());
+		Some(text)
+	}
+}
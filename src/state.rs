@@ -0,0 +1,148 @@
+//! Persistent bridge state
+//!
+//! Choices made by commands at runtime which survive restarts, stored as TOML.
+//! The file contains the password of a channel chosen by `/ts_channel`, exports don't.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::access::AccessConfig;
+
+pub type StateHandle = Arc<std::sync::Mutex<StateStore>>;
+
+/// Highest volume of a single speaker in percent
+pub const MAX_USER_VOLUME: u32 = 200;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BridgeState {
+	/// Teamspeak channel chosen by `/ts_channel`, overrides the config
+	pub ts_channel: Option<TsChannelChoice>,
+	/// Speaker settings by discord user ID
+	pub discord_users: BTreeMap<String, UserSettings>,
+	/// Speaker settings by teamspeak client unique ID
+	pub ts_users: BTreeMap<String, UserSettings>,
+	/// Access rules changed by `/access`, override the config
+	pub access: Option<AccessConfig>,
+	/// Discord user followed by `/follow`, by guild ID
+	pub discord_follow: BTreeMap<String, u64>,
+	/// Unique ID of the teamspeak client followed by `/follow`
+	pub ts_follow: Option<String>,
+}
+
+/// Volume and mute of a single speaker
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(default)]
+pub struct UserSettings {
+	/// In percent
+	pub volume: u32,
+	pub muted: bool,
+}
+
+impl Default for UserSettings {
+	fn default() -> Self { Self { volume: 100, muted: false } }
+}
+
+impl UserSettings {
+	/// Gain of the audio of this user, silent while muted
+	pub fn gain(&self) -> f32 { if self.muted { 0.0 } else { self.volume as f32 / 100.0 } }
+}
+
+impl BridgeState {
+	pub fn discord_user(&self, user: u64) -> UserSettings {
+		self.discord_users.get(&user.to_string()).copied().unwrap_or_default()
+	}
+
+	pub fn ts_user(&self, uid: &str) -> UserSettings { self.ts_users.get(uid).copied().unwrap_or_default() }
+
+	/// Change the settings of a discord user, default settings are not stored
+	pub fn update_discord_user(&mut self, user: u64, change: impl FnOnce(&mut UserSettings)) {
+		update_user(&mut self.discord_users, user.to_string(), change);
+	}
+
+	pub fn update_ts_user(&mut self, uid: &str, change: impl FnOnce(&mut UserSettings)) {
+		update_user(&mut self.ts_users, uid.to_string(), change);
+	}
+}
+
+fn update_user(users: &mut BTreeMap<String, UserSettings>, key: String, change: impl FnOnce(&mut UserSettings)) {
+	let settings = users.entry(key.clone()).or_default();
+	change(settings);
+	if *settings == UserSettings::default() {
+		users.remove(&key);
+	}
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TsChannelChoice {
+	pub id: u64,
+	pub password: Option<String>,
+}
+
+pub struct StateStore {
+	path: PathBuf,
+	state: BridgeState,
+	/// Increased on every change
+	generation: u64,
+}
+
+impl StateStore {
+	/// Load the state, a missing file is an empty state.
+	pub fn load(path: PathBuf) -> Result<Self> {
+		let state = match std::fs::read_to_string(&path) {
+			Ok(v) => toml::from_str(&v).with_context(|| format!("Invalid state file {}", path.display()))?,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => BridgeState::default(),
+			Err(e) => return Err(e).with_context(|| format!("Can't read state file {}", path.display())),
+		};
+		Ok(Self { path, state, generation: 0 })
+	}
+
+	pub fn state(&self) -> &BridgeState { &self.state }
+
+	/// Changes whenever the state changes
+	pub fn generation(&self) -> u64 { self.generation }
+
+	/// The whole state as TOML without secrets, for backups
+	pub fn export(&self) -> Result<String> {
+		let mut state = self.state.clone();
+		if let Some(channel) = state.ts_channel.as_mut() {
+			channel.password = None;
+		}
+		Ok(toml::to_string(&state)?)
+	}
+
+	/// Replace the whole state with an exported one
+	///
+	/// Keeps the channel password if the exported channel is the current one.
+	pub fn import(&mut self, exported: &str) -> Result<()> {
+		let mut state: BridgeState = toml::from_str(exported).context("Invalid settings file")?;
+		for users in [&mut state.discord_users, &mut state.ts_users] {
+			if let Some((user, settings)) = users.iter().find(|(_, s)| s.volume > MAX_USER_VOLUME) {
+				bail!("Volume {}% of {} is above {}%", settings.volume, user, MAX_USER_VOLUME);
+			}
+			// like changes by commands, default settings are not stored
+			users.retain(|_, s| *s != UserSettings::default());
+		}
+		if let (Some(new), Some(current)) = (state.ts_channel.as_mut(), self.state.ts_channel.as_ref()) {
+			if new.id == current.id && new.password.is_none() {
+				new.password = current.password.clone();
+			}
+		}
+		self.update(|s| *s = state)
+	}
+
+	/// Change the state and write it to disk.
+	pub fn update(&mut self, change: impl FnOnce(&mut BridgeState)) -> Result<()> {
+		change(&mut self.state);
+		self.generation += 1;
+		// write a temporary file first, so a crash can't leave a broken state behind
+		let tmp = self.path.with_extension("tmp");
+		std::fs::write(&tmp, toml::to_string(&self.state)?)?;
+		std::fs::rename(&tmp, &self.path)?;
+		Ok(())
+	}
+}
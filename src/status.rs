@@ -0,0 +1,194 @@
+//! Bridge status
+//!
+//! Health of both sides, collected by the pipelines and shown by `/bridge status`.
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Amount of recent warnings to keep
+const MAX_WARNINGS: usize = 5;
+/// Longest timed pause
+pub const MAX_PAUSE_MINUTES: u64 = 24 * 60;
+/// Larger jumps in packet ids are a restarted stream, not a loss.
+const MAX_PACKET_GAP: u16 = 100;
+
+pub type StatusHandle = Arc<std::sync::Mutex<BridgeStatus>>;
+
+/// Teamspeak connection details
+#[derive(Clone, Debug)]
+pub struct TsConnection {
+	pub server: String,
+	pub channel: String,
+}
+
+/// Statistics of one direction
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DirectionStats {
+	/// Speakers mixed into the last frame
+	pub active_speakers: usize,
+	/// Average jitter buffer depth per speaker, if known
+	pub buffered_ms: Option<f32>,
+	pub received_packets: u64,
+	pub lost_packets: u64,
+	/// Gain reduction of compressor and limiter in the last frame, in dB
+	pub gain_reduction_db: f32,
+}
+
+/// Directions in which forwarding is paused
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Paused {
+	pub discord_to_ts: bool,
+	pub ts_to_discord: bool,
+	/// When the directions resume by themselves
+	pub discord_to_ts_until: Option<Instant>,
+	pub ts_to_discord_until: Option<Instant>,
+}
+
+/// Forwarding direction for pause and resume
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+	DiscordToTs,
+	TsToDiscord,
+	Both,
+}
+
+pub struct BridgeStatus {
+	pub started: Instant,
+	/// `None` while not connected
+	pub teamspeak: Option<TsConnection>,
+	pub paused: Paused,
+	pub discord_to_ts: DirectionStats,
+	pub ts_to_discord: DirectionStats,
+	warnings: VecDeque<(Instant, String)>,
+}
+
+impl BridgeStatus {
+	pub fn new() -> Self {
+		Self {
+			started: Instant::now(),
+			teamspeak: None,
+			paused: Default::default(),
+			discord_to_ts: Default::default(),
+			ts_to_discord: Default::default(),
+			warnings: Default::default(),
+		}
+	}
+
+	/// Print a warning and keep it for the status.
+	pub fn warn(&mut self, message: String) {
+		eprintln!("{}", message);
+		if self.warnings.len() >= MAX_WARNINGS {
+			self.warnings.pop_front();
+		}
+		self.warnings.push_back((Instant::now(), message));
+	}
+
+	/// Recent warnings, oldest first
+	pub fn warnings(&self) -> impl Iterator<Item = &(Instant, String)> { self.warnings.iter() }
+}
+
+impl Paused {
+	/// Pause or resume a direction, paused directions resume after `duration` if set.
+	pub fn set(&mut self, direction: Direction, pause: bool, duration: Option<Duration>) {
+		let until = if pause { duration.and_then(|d| Instant::now().checked_add(d)) } else { None };
+		if direction != Direction::TsToDiscord {
+			self.discord_to_ts = pause;
+			self.discord_to_ts_until = until;
+		}
+		if direction != Direction::DiscordToTs {
+			self.ts_to_discord = pause;
+			self.ts_to_discord_until = until;
+		}
+	}
+
+	/// Resume directions whose timer ran out
+	pub fn expire(&mut self) {
+		let now = Instant::now();
+		if self.discord_to_ts_until.map(|t| t <= now).unwrap_or_default() {
+			self.discord_to_ts = false;
+			self.discord_to_ts_until = None;
+		}
+		if self.ts_to_discord_until.map(|t| t <= now).unwrap_or_default() {
+			self.ts_to_discord = false;
+			self.ts_to_discord_until = None;
+		}
+	}
+}
+
+impl std::str::FromStr for Direction {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> anyhow::Result<Self> {
+		match s {
+			"discord_to_ts" => Ok(Self::DiscordToTs),
+			"ts_to_discord" => Ok(Self::TsToDiscord),
+			"both" => Ok(Self::Both),
+			_ => anyhow::bail!("Unknown direction {}", s),
+		}
+	}
+}
+
+impl DirectionStats {
+	/// Lost packets in percent of all expected packets
+	pub fn loss_percent(&self) -> f64 {
+		let expected = self.received_packets + self.lost_packets;
+		if expected == 0 {
+			0.0
+		} else {
+			self.lost_packets as f64 * 100.0 / expected as f64
+		}
+	}
+}
+
+pub fn format_duration(duration: Duration) -> String {
+	let secs = duration.as_secs();
+	match secs {
+		0..=59 => format!("{}s", secs),
+		60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+		_ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+	}
+}
+
+/// Counts lost packets by gaps in the packet ids of every sender.
+///
+/// Packets arriving late fill their gap again, duplicates are not counted.
+pub struct LossCounter<Id> {
+	last_ids: HashMap<Id, u16>,
+	pub received: u64,
+	pub lost: u64,
+}
+
+impl<Id> Default for LossCounter<Id> {
+	fn default() -> Self { Self { last_ids: HashMap::new(), received: 0, lost: 0 } }
+}
+
+impl<Id: Eq + Hash> LossCounter<Id> {
+	pub fn record(&mut self, id: Id, packet_id: u16) {
+		let last = match self.last_ids.get_mut(&id) {
+			Some(last) => last,
+			None => {
+				self.received += 1;
+				self.last_ids.insert(id, packet_id);
+				return;
+			}
+		};
+		let gap = packet_id.wrapping_sub(*last);
+		if gap == 0 {
+			// duplicate
+			return;
+		}
+		self.received += 1;
+		if gap < MAX_PACKET_GAP {
+			self.lost += u64::from(gap - 1);
+			*last = packet_id;
+		} else if gap > u16::MAX - MAX_PACKET_GAP {
+			// reordered, it was counted as lost before
+			self.lost = self.lost.saturating_sub(1);
+		} else {
+			// restarted stream
+			*last = packet_id;
+		}
+	}
+}